@@ -0,0 +1,137 @@
+#!/usr/bin/env python3
+"""Generate src/unicode_tables.rs from the Unicode Character Database.
+
+Usage: generate_unicode_tables.py UCD_DIR > src/unicode_tables.rs
+
+UCD_DIR must hold extracted/DerivedGeneralCategory.txt (or a copy at the
+top level), Scripts.txt and PropertyValueAliases.txt, as published at
+https://www.unicode.org/Public/UCD/latest/ucd/
+"""
+
+import os
+import re
+import sys
+
+# Surrogates can't appear in a Rust char, and unassigned code points (Cn)
+# are derived at runtime as everything not in another category
+SKIPPED_CATEGORIES = {"Cs", "Cn"}
+
+LINE = re.compile(r"^([0-9A-F]+)(?:\.\.([0-9A-F]+))?\s*;\s*(\w+)")
+
+
+def find(ucd_dir, *names):
+    for name in names:
+        path = os.path.join(ucd_dir, name)
+        if os.path.exists(path):
+            return path
+    sys.exit(f"missing {names[0]} in {ucd_dir}")
+
+
+def version(path):
+    with open(path, encoding="utf-8") as f:
+        match = re.search(r"-(\d+\.\d+\.\d+)\.txt", f.readline())
+    return match.group(1) if match else "unknown"
+
+
+def read_ranges(path):
+    """Map each property value to its sorted, merged (start, end) ranges."""
+    by_value = {}
+    with open(path, encoding="utf-8") as f:
+        for line in f:
+            match = LINE.match(line)
+            if not match:
+                continue
+            start = int(match.group(1), 16)
+            end = int(match.group(2) or match.group(1), 16)
+            by_value.setdefault(match.group(3), []).append((start, end))
+    return {value: merge(ranges) for value, ranges in by_value.items()}
+
+
+def merge(ranges):
+    merged = []
+    for start, end in sorted(ranges):
+        if merged and start <= merged[-1][1] + 1:
+            merged[-1] = (merged[-1][0], max(merged[-1][1], end))
+        else:
+            merged.append((start, end))
+    return merged
+
+
+def read_script_aliases(path):
+    """Pairs of (alias, long name) for every script with a short name."""
+    aliases = []
+    with open(path, encoding="utf-8") as f:
+        for line in f:
+            fields = [field.strip() for field in line.split("#")[0].split(";")]
+            if len(fields) < 3 or fields[0] != "sc":
+                continue
+            long_name = fields[2]
+            for alias in [fields[1]] + fields[3:]:
+                if alias and alias != long_name:
+                    aliases.append((alias, long_name))
+    return sorted(aliases)
+
+
+def rust_char(code_point):
+    return f"'\\u{{{code_point:X}}}'"
+
+
+def emit_table(out, name, doc, tables):
+    out.write(f"/// {doc}\n")
+    out.write(f"pub const {name}: &[(&str, &[(char, char)])] = &[\n")
+    for value, ranges in sorted(tables.items()):
+        out.write(f"    (\"{value}\", &[\n")
+        pairs = [f"({rust_char(s)}, {rust_char(e)})" for s, e in ranges]
+        line = "       "
+        for pair in pairs:
+            if len(line) + len(pair) + 2 > 100:
+                out.write(line.rstrip() + "\n")
+                line = "       "
+            line += f" {pair},"
+        out.write(line + "\n")
+        out.write("    ]),\n")
+    out.write("];\n")
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    ucd_dir = sys.argv[1]
+
+    category_path = find(
+        ucd_dir, "extracted/DerivedGeneralCategory.txt", "DerivedGeneralCategory.txt"
+    )
+    script_path = find(ucd_dir, "Scripts.txt")
+    alias_path = find(ucd_dir, "PropertyValueAliases.txt")
+
+    categories = {
+        value: ranges
+        for value, ranges in read_ranges(category_path).items()
+        if value not in SKIPPED_CATEGORIES
+    }
+    scripts = read_ranges(script_path)
+    aliases = [(a, s) for a, s in read_script_aliases(alias_path) if s in scripts]
+
+    out = sys.stdout
+    out.write(
+        "// Generated by scripts/generate_unicode_tables.py from the Unicode "
+        f"{version(script_path)}\n"
+        "// Character Database. Do not edit by hand.\n\n"
+    )
+    emit_table(
+        out,
+        "GENERAL_CATEGORIES",
+        "Ranges of each two-letter general category, except Cs and Cn",
+        categories,
+    )
+    out.write("\n")
+    emit_table(out, "SCRIPTS", "Ranges of each script, by long name", scripts)
+    out.write("\n/// Short script names like `Grek`, paired with the long name\n")
+    out.write("pub const SCRIPT_ALIASES: &[(&str, &str)] = &[\n")
+    for alias, script in aliases:
+        out.write(f"    (\"{alias}\", \"{script}\"),\n")
+    out.write("];\n")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,647 @@
+//! Backtracking matcher for patterns the Pike VM can't run
+//!
+//! Backreferences, lookaround and atomic groups need a matcher that follows
+//! one path at a time. Patterns compile to a small program like the Pike
+//! VM's, but repetition keeps a counter instead of copying its body, and
+//! the choice points still to try live on an explicit stack, so a long line
+//! can't overflow the call stack. Lookaround and atomic bodies run as
+//! separate sub-searches, nested only as deeply as the pattern itself.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::charset::fold_case;
+use crate::pikevm::Slots;
+use crate::token::{has_backreferences, Token};
+use crate::Error;
+
+/// A single backtracking instruction
+#[derive(Debug, Clone)]
+enum Inst {
+    Consume(Token),      // Match one char against a leaf token
+    Assert(Token),       // Zero-width anchor or word boundary
+    Split(usize, usize), // Fork, preferring the first target
+    Jump(usize),         // Continue at another instruction
+    Save(usize),         // Record the current position in a capture slot
+    OpenGroup(usize),    // Record where a group starts, pending its close
+    CloseGroup(usize),   // Set a group's span from its pending start
+    // Match the text group `group` captured
+    Backref {
+        group: usize,
+        ignore_case: bool,
+    },
+    RepeatStart(usize), // Reset a repeat's iteration count
+    // Run another iteration starting at the next instruction, or go on to
+    // `exit`, depending on the count and greediness
+    RepeatLoop {
+        repeat: usize,
+        exit: usize,
+    },
+    RepeatMark(usize), // Record where an iteration started
+    // Count the iteration, then go back to `start` unless it was empty
+    RepeatEnd {
+        repeat: usize,
+        start: usize,
+        exit: usize,
+    },
+    // Sub-searches, with the body compiled separately at `body`
+    LookAhead {
+        body: usize,
+        negated: bool,
+    },
+    LookBehind {
+        body: usize,
+        negated: bool,
+        max_width: Option<usize>,
+    },
+    Atomic(usize),
+    Match, // The pattern or a sub-search body matched
+}
+
+/// Bounds of a counted repetition
+#[derive(Debug, Clone)]
+struct Repeat {
+    min: usize,
+    max: Option<usize>,
+    greedy: bool,
+}
+
+/// A compiled pattern for backtracking
+#[derive(Debug, Clone)]
+pub struct Backtracker {
+    insts: Vec<Inst>,
+    repeats: Vec<Repeat>,
+    // Capture slots, followed by one pending start per group
+    slots: usize,
+    // Without backreferences, whether a path succeeds depends only on
+    // where it is and not on what the groups captured, so failed states
+    // can be remembered
+    memoize: bool,
+}
+
+impl Backtracker {
+    /// Compile a token tree with `groups` capture groups, resolving named
+    /// backreferences through `group_names`
+    pub fn new(tokens: &[Token], groups: usize, group_names: &HashMap<String, usize>) -> Self {
+        let mut compiler = Compiler {
+            insts: Vec::new(),
+            repeats: Vec::new(),
+            group_names,
+            bodies: Vec::new(),
+        };
+        compiler.push(Inst::Save(0));
+        compiler.sequence(tokens);
+        compiler.push(Inst::Save(1));
+        compiler.push(Inst::Match);
+
+        // Sub-search bodies go after the main program, each ending in its
+        // own `Match`
+        while let Some((at, tokens)) = compiler.bodies.pop() {
+            let start = compiler.insts.len();
+            compiler.sequence(tokens);
+            compiler.push(Inst::Match);
+            match &mut compiler.insts[at] {
+                Inst::LookAhead { body, .. }
+                | Inst::LookBehind { body, .. }
+                | Inst::Atomic(body) => *body = start,
+                _ => unreachable!("only sub-searches have bodies"),
+            }
+        }
+
+        Self {
+            insts: compiler.insts,
+            repeats: compiler.repeats,
+            slots: 2 * (groups + 1),
+            memoize: !has_backreferences(tokens),
+        }
+    }
+
+    /// Find the leftmost-first match starting at one of `starts`, trying
+    /// them in order. Gives up with `Error::TooComplex` if any one start
+    /// takes `step_limit` choice points, unless the limit is 0, so long
+    /// lines get as much room per start as short ones.
+    pub fn search(
+        &self,
+        chars: &[char],
+        starts: impl IntoIterator<Item = usize>,
+        ascii: bool,
+        step_limit: usize,
+    ) -> Result<Option<Slots>, Error> {
+        let groups = self.slots / 2;
+        let mut state = State {
+            program: self,
+            chars,
+            ascii,
+            step_limit,
+            steps: 0,
+            slots: vec![None; self.slots + groups],
+            counts: vec![0; self.repeats.len()],
+            marks: vec![0; self.repeats.len()],
+            stack: Vec::new(),
+            results: HashMap::new(),
+        };
+        // One memo for every start, since they all share the same program
+        let mut visited = HashSet::new();
+
+        for start in starts {
+            state.steps = 0;
+            if state.run(0, start, None, &mut visited).is_some() {
+                state.slots.truncate(self.slots);
+                return Ok(Some(state.slots));
+            }
+            if state.out_of_steps() {
+                return Err(Error::TooComplex(step_limit));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Pending work on the backtracking stack
+enum Frame {
+    Explore(usize, usize), // Try instruction `pc` at position `pos`
+    RestoreSlot(usize, Option<usize>),
+    RestoreCount(usize, usize),
+    RestoreMark(usize, usize),
+}
+
+/// A choice point already explored: the instruction, the position, and
+/// for every repeat its count and whether its iteration started here
+type Visited = HashSet<(usize, usize, Vec<usize>)>;
+
+/// Where a sub-search body's match ended, and the slots it changed to
+/// apply on top of the caller's
+type SubMatch = (usize, Vec<(usize, Option<usize>)>);
+
+/// Everything that changes during one search
+struct State<'a> {
+    program: &'a Backtracker,
+    chars: &'a [char],
+    ascii: bool,
+    step_limit: usize,
+    steps: usize,
+    slots: Slots,
+    counts: Vec<usize>,
+    marks: Vec<usize>,
+    // Shared by nested runs, each using the frames above where it started
+    stack: Vec<Frame>,
+    // Outcome of each sub-search by body, start and required end, when
+    // memoizing
+    results: HashMap<(usize, usize, Option<usize>), Option<SubMatch>>,
+}
+
+impl State<'_> {
+    /// Run the program from `pc` at `pos`, returning where the first match
+    /// ends. A match must end at `must_end` if it's given. On success the
+    /// slots hold the match's captures; on failure everything is restored.
+    fn run(
+        &mut self,
+        pc: usize,
+        pos: usize,
+        must_end: Option<usize>,
+        visited: &mut Visited,
+    ) -> Option<usize> {
+        let program = self.program;
+        let base = self.stack.len();
+        self.stack.push(Frame::Explore(pc, pos));
+
+        while self.stack.len() > base {
+            let frame = self.stack.pop().expect("the stack is above its base");
+            let (mut pc, mut pos) = match frame {
+                Frame::Explore(pc, pos) => (pc, pos),
+                Frame::RestoreSlot(slot, value) => {
+                    self.slots[slot] = value;
+                    continue;
+                }
+                Frame::RestoreCount(repeat, count) => {
+                    self.counts[repeat] = count;
+                    continue;
+                }
+                Frame::RestoreMark(repeat, mark) => {
+                    self.marks[repeat] = mark;
+                    continue;
+                }
+            };
+
+            // Follow this path until it fails, pushing the alternatives
+            loop {
+                match &program.insts[pc] {
+                    Inst::Consume(token) => {
+                        if pos < self.chars.len() && token.matches_char(self.chars[pos], self.ascii)
+                        {
+                            pc += 1;
+                            pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+
+                    Inst::Assert(token) => {
+                        if !token.assertion_holds(self.chars, pos, self.ascii) {
+                            break;
+                        }
+                        pc += 1;
+                    }
+
+                    Inst::Split(first, second) => {
+                        if !self.choose(pc, pos, visited) {
+                            break;
+                        }
+                        self.stack.push(Frame::Explore(*second, pos));
+                        pc = *first;
+                    }
+
+                    Inst::Jump(target) => pc = *target,
+
+                    Inst::Save(slot) => {
+                        self.stack
+                            .push(Frame::RestoreSlot(*slot, self.slots[*slot]));
+                        self.slots[*slot] = Some(pos);
+                        pc += 1;
+                    }
+
+                    Inst::OpenGroup(group) => {
+                        let pending = program.slots + group;
+                        self.stack
+                            .push(Frame::RestoreSlot(pending, self.slots[pending]));
+                        self.slots[pending] = Some(pos);
+                        pc += 1;
+                    }
+
+                    // Both ends change together, so a backreference inside
+                    // the group sees the previous iteration's capture
+                    Inst::CloseGroup(group) => {
+                        let start = self.slots[program.slots + group];
+                        for (slot, value) in [(2 * group, start), (2 * group + 1, Some(pos))] {
+                            self.stack.push(Frame::RestoreSlot(slot, self.slots[slot]));
+                            self.slots[slot] = value;
+                        }
+                        pc += 1;
+                    }
+
+                    Inst::Backref { group, ignore_case } => {
+                        match self.match_captured(*group, *ignore_case, pos) {
+                            Some(end) => pos = end,
+                            None => break,
+                        }
+                        pc += 1;
+                    }
+
+                    Inst::RepeatStart(repeat) => {
+                        self.stack
+                            .push(Frame::RestoreCount(*repeat, self.counts[*repeat]));
+                        self.counts[*repeat] = 0;
+                        pc += 1;
+                    }
+
+                    Inst::RepeatLoop { repeat, exit } => {
+                        let Repeat { min, max, greedy } = program.repeats[*repeat];
+                        let count = self.counts[*repeat];
+                        if count < min {
+                            pc += 1;
+                        } else if max == Some(count) {
+                            pc = *exit;
+                        } else {
+                            if !self.choose(pc, pos, visited) {
+                                break;
+                            }
+                            let (first, second) = if greedy {
+                                (pc + 1, *exit)
+                            } else {
+                                (*exit, pc + 1)
+                            };
+                            self.stack.push(Frame::Explore(second, pos));
+                            pc = first;
+                        }
+                    }
+
+                    Inst::RepeatMark(repeat) => {
+                        self.stack
+                            .push(Frame::RestoreMark(*repeat, self.marks[*repeat]));
+                        self.marks[*repeat] = pos;
+                        pc += 1;
+                    }
+
+                    Inst::RepeatEnd {
+                        repeat,
+                        start,
+                        exit,
+                    } => {
+                        let Repeat { min, max, .. } = program.repeats[*repeat];
+                        let count = self.counts[*repeat];
+                        self.stack.push(Frame::RestoreCount(*repeat, count));
+                        // Without a maximum, all counts past the minimum
+                        // behave the same
+                        self.counts[*repeat] = match max {
+                            Some(_) => count + 1,
+                            None => (count + 1).min(min),
+                        };
+                        // Once the minimum is met another empty iteration
+                        // would repeat forever, so keep what this one
+                        // captured and end the loop here
+                        pc = if self.marks[*repeat] == pos && count >= min {
+                            *exit
+                        } else {
+                            *start
+                        };
+                    }
+
+                    Inst::LookAhead { body, negated } => {
+                        let found = self.sub_search(*body, pos, None);
+                        if !self.apply_lookaround(found, *negated) {
+                            break;
+                        }
+                        pc += 1;
+                    }
+
+                    Inst::LookBehind {
+                        body,
+                        negated,
+                        max_width,
+                    } => {
+                        // Only starts close enough for the body to end
+                        // exactly at pos
+                        let earliest = max_width.map_or(0, |width| pos.saturating_sub(width));
+                        let found = (earliest..=pos)
+                            .find_map(|start| self.sub_search(*body, start, Some(pos)));
+                        if !self.apply_lookaround(found, *negated) {
+                            break;
+                        }
+                        pc += 1;
+                    }
+
+                    Inst::Atomic(body) => {
+                        // Commit to the first way the body matches
+                        let Some((end, changes)) = self.sub_search(*body, pos, None) else {
+                            break;
+                        };
+                        self.apply_changes(changes);
+                        pc += 1;
+                        pos = end;
+                    }
+
+                    Inst::Match => {
+                        if must_end.is_some_and(|end| end != pos) {
+                            break;
+                        }
+                        // Keep the captures by dropping their restores
+                        self.stack.truncate(base);
+                        return Some(pos);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Count a choice point against the step limit and check it hasn't
+    /// been explored before, returning false if this path should stop
+    fn choose(&mut self, pc: usize, pos: usize, visited: &mut Visited) -> bool {
+        if self.out_of_steps() {
+            return false;
+        }
+        self.steps += 1;
+        if !self.program.memoize {
+            return true;
+        }
+
+        let repeats = self
+            .counts
+            .iter()
+            .zip(&self.marks)
+            .map(|(&count, &mark)| 2 * count + usize::from(mark == pos))
+            .collect();
+        visited.insert((pc, pos, repeats))
+    }
+
+    fn out_of_steps(&self) -> bool {
+        self.step_limit != 0 && self.steps >= self.step_limit
+    }
+
+    /// Match the text of group `group` at `pos`, returning where it ends.
+    /// A group that hasn't captured anything never matches.
+    fn match_captured(&self, group: usize, ignore_case: bool, pos: usize) -> Option<usize> {
+        let start = self.slots[2 * group]?;
+        let end = self.slots[2 * group + 1]?;
+        let captured = &self.chars[start..end];
+        let end = pos + captured.len();
+        let text = self.chars.get(pos..end)?;
+
+        let same = if ignore_case {
+            text.iter()
+                .zip(captured)
+                .all(|(&a, &b)| fold_case(a) == fold_case(b))
+        } else {
+            text == captured
+        };
+        same.then_some(end)
+    }
+
+    /// Run a lookaround or atomic body from `pos`, returning where it ended
+    /// and the slots it changed. Everything else is left as it was.
+    fn sub_search(&mut self, body: usize, pos: usize, must_end: Option<usize>) -> Option<SubMatch> {
+        let key = (body, pos, must_end);
+        if let Some(result) = self.results.get(&key) {
+            return result.clone();
+        }
+
+        let slots = self.slots.clone();
+        let counts = self.counts.clone();
+        let marks = self.marks.clone();
+        // The body's choice points don't lead on to the caller's, so they
+        // get a memo of their own
+        let result = self
+            .run(body, pos, must_end, &mut HashSet::new())
+            .map(|end| {
+                let changes = (0..slots.len())
+                    .filter(|&slot| self.slots[slot] != slots[slot])
+                    .map(|slot| (slot, self.slots[slot]))
+                    .collect();
+                (end, changes)
+            });
+        self.slots = slots;
+        self.counts = counts;
+        self.marks = marks;
+
+        if self.program.memoize {
+            self.results.insert(key, result.clone());
+        }
+        result
+    }
+
+    /// Decide whether a lookaround holds, keeping the groups a positive one
+    /// captured. Returns false if the path should stop.
+    fn apply_lookaround(&mut self, found: Option<SubMatch>, negated: bool) -> bool {
+        match found {
+            Some(_) if negated => false,
+            Some((_, changes)) => {
+                self.apply_changes(changes);
+                true
+            }
+            None => negated,
+        }
+    }
+
+    /// Apply a sub-search's captures, undoing them if the path backtracks
+    fn apply_changes(&mut self, changes: Vec<(usize, Option<usize>)>) {
+        for (slot, value) in changes {
+            self.stack.push(Frame::RestoreSlot(slot, self.slots[slot]));
+            self.slots[slot] = value;
+        }
+    }
+}
+
+struct Compiler<'a> {
+    insts: Vec<Inst>,
+    repeats: Vec<Repeat>,
+    group_names: &'a HashMap<String, usize>,
+    // Sub-search instructions still waiting for their bodies
+    bodies: Vec<(usize, &'a [Token])>,
+}
+
+impl<'a> Compiler<'a> {
+    /// Append an instruction and return its address
+    fn push(&mut self, inst: Inst) -> usize {
+        self.insts.push(inst);
+        self.insts.len() - 1
+    }
+
+    fn sequence(&mut self, tokens: &'a [Token]) {
+        for token in tokens {
+            self.token(token);
+        }
+    }
+
+    fn token(&mut self, token: &'a Token) {
+        match token {
+            Token::Char(_)
+            | Token::Dot
+            | Token::Digit
+            | Token::Word
+            | Token::Whitespace
+            | Token::CharClass(_)
+            | Token::NegCharClass(_) => {
+                self.push(Inst::Consume(token.clone()));
+            }
+
+            Token::StartAnchor
+            | Token::EndAnchor
+            | Token::EndOfText
+            | Token::WordBoundary
+            | Token::NotWordBoundary
+            | Token::StartOfWord
+            | Token::EndOfWord => {
+                self.push(Inst::Assert(token.clone()));
+            }
+
+            Token::IgnoreCase(inner) => match inner.as_ref() {
+                Token::Backreference(_) | Token::NamedBackreference(_) => {
+                    self.backreference(inner, true)
+                }
+                _ => {
+                    self.push(Inst::Consume(token.clone()));
+                }
+            },
+
+            Token::Backreference(_) | Token::NamedBackreference(_) => {
+                self.backreference(token, false)
+            }
+
+            Token::Group(tokens, group_num) => {
+                self.push(Inst::OpenGroup(*group_num));
+                self.sequence(tokens);
+                self.push(Inst::CloseGroup(*group_num));
+            }
+
+            Token::NonCapturing(tokens) => self.sequence(tokens),
+
+            Token::Alternative(alternatives) => self.alternation(alternatives),
+
+            Token::Repeat {
+                token,
+                min,
+                max,
+                greedy,
+            } => self.repeat(token, *min, *max, *greedy),
+
+            Token::LookAhead { tokens, negated } => {
+                let at = self.push(Inst::LookAhead {
+                    body: 0,
+                    negated: *negated,
+                });
+                self.bodies.push((at, tokens));
+            }
+
+            Token::LookBehind {
+                tokens,
+                negated,
+                max_width,
+            } => {
+                let at = self.push(Inst::LookBehind {
+                    body: 0,
+                    negated: *negated,
+                    max_width: *max_width,
+                });
+                self.bodies.push((at, tokens));
+            }
+
+            Token::Atomic(tokens) => {
+                let at = self.push(Inst::Atomic(0));
+                self.bodies.push((at, tokens));
+            }
+        }
+    }
+
+    fn backreference(&mut self, token: &Token, ignore_case: bool) {
+        let group = match token {
+            Token::Backreference(group) => *group,
+            Token::NamedBackreference(name) => self.group_names[name],
+            _ => unreachable!("not a backreference"),
+        };
+        self.push(Inst::Backref { group, ignore_case });
+    }
+
+    fn alternation(&mut self, alternatives: &'a [Vec<Token>]) {
+        let mut exits = Vec::new();
+
+        for (i, alternative) in alternatives.iter().enumerate() {
+            if i + 1 == alternatives.len() {
+                self.sequence(alternative);
+                break;
+            }
+            let split = self.push(Inst::Split(0, 0));
+            self.sequence(alternative);
+            exits.push(self.push(Inst::Jump(0)));
+            let next = self.insts.len();
+            self.insts[split] = Inst::Split(split + 1, next);
+        }
+
+        let end = self.insts.len();
+        for exit in exits {
+            self.insts[exit] = Inst::Jump(end);
+        }
+    }
+
+    fn repeat(&mut self, token: &'a Token, min: usize, max: Option<usize>, greedy: bool) {
+        let repeat = self.repeats.len();
+        self.repeats.push(Repeat { min, max, greedy });
+
+        self.push(Inst::RepeatStart(repeat));
+        let start = self.push(Inst::RepeatLoop { repeat, exit: 0 });
+        self.push(Inst::RepeatMark(repeat));
+        self.token(token);
+        let end = self.push(Inst::RepeatEnd {
+            repeat,
+            start,
+            exit: 0,
+        });
+
+        let exit = self.insts.len();
+        self.insts[start] = Inst::RepeatLoop { repeat, exit };
+        self.insts[end] = Inst::RepeatEnd {
+            repeat,
+            start,
+            exit,
+        };
+    }
+}
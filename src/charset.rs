@@ -0,0 +1,219 @@
+//! Character sets, the shorthand classes and case folding
+
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+
+use crate::unicode;
+
+/// A set of chars stored as sorted ranges that neither overlap nor touch,
+/// so membership is a binary search
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CharSet {
+    ranges: Vec<(char, char)>,
+}
+
+impl CharSet {
+    pub fn from_ranges(ranges: impl IntoIterator<Item = (char, char)>) -> Self {
+        let mut ranges: Vec<(char, char)> = ranges.into_iter().collect();
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if next_char(last.1).map_or(true, |after| start <= after) => {
+                    last.1 = last.1.max(end);
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    pub fn into_ranges(self) -> Vec<(char, char)> {
+        self.ranges
+    }
+
+    pub fn union(&self, other: &CharSet) -> CharSet {
+        CharSet::from_ranges(self.ranges.iter().chain(&other.ranges).copied())
+    }
+
+    pub fn contains(&self, ch: char) -> bool {
+        self.ranges
+            .binary_search_by(|&(start, end)| {
+                if end < ch {
+                    Ordering::Less
+                } else if start > ch {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn intersection(&self, other: &CharSet) -> CharSet {
+        self.negated().union(&other.negated()).negated()
+    }
+
+    pub fn difference(&self, other: &CharSet) -> CharSet {
+        self.intersection(&other.negated())
+    }
+
+    pub fn symmetric_difference(&self, other: &CharSet) -> CharSet {
+        self.difference(other).union(&other.difference(self))
+    }
+
+    /// Every char not in this set
+    pub fn negated(&self) -> CharSet {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 1);
+        let mut gap_start = Some('\0');
+
+        for &(start, end) in &self.ranges {
+            if let (Some(gap), Some(gap_end)) = (gap_start, prev_char(start)) {
+                if gap <= gap_end {
+                    ranges.push((gap, gap_end));
+                }
+            }
+            gap_start = next_char(end);
+        }
+        if let Some(gap) = gap_start {
+            ranges.push((gap, char::MAX));
+        }
+        CharSet { ranges }
+    }
+
+    /// This set plus the case-folded form of every char in it
+    pub fn with_folded(&self) -> CharSet {
+        let folded = case_fold_pairs()
+            .iter()
+            .filter(|&&(ch, _)| self.contains(ch))
+            .map(|&(_, folded)| (folded, folded));
+        CharSet::from_ranges(self.ranges.iter().copied().chain(folded))
+    }
+}
+
+/// The char after `ch`, skipping the surrogate gap
+fn next_char(ch: char) -> Option<char> {
+    match ch {
+        '\u{D7FF}' => Some('\u{E000}'),
+        _ => char::from_u32(ch as u32 + 1),
+    }
+}
+
+/// The char before `ch`, skipping the surrogate gap
+fn prev_char(ch: char) -> Option<char> {
+    match ch {
+        '\u{E000}' => Some('\u{D7FF}'),
+        _ => char::from_u32((ch as u32).checked_sub(1)?),
+    }
+}
+
+/// Backslash classes that can appear inside and outside brackets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shorthand {
+    Digit,      // \d
+    Word,       // \w
+    Whitespace, // \s
+}
+
+impl Shorthand {
+    /// Whether `ch` is in the class. ASCII mode keeps all three classes to
+    /// ASCII; otherwise they follow Unicode
+    pub fn matches(self, ch: char, ascii: bool) -> bool {
+        match self {
+            Shorthand::Digit => {
+                ch.is_ascii_digit() || (!ascii && !ch.is_ascii() && unicode::digit().contains(ch))
+            }
+            Shorthand::Word => {
+                is_word_char(ch) || (!ascii && !ch.is_ascii() && unicode::word().contains(ch))
+            }
+            Shorthand::Whitespace => {
+                matches!(ch, '\t'..='\r' | ' ') || (!ascii && ch.is_whitespace())
+            }
+        }
+    }
+
+    pub fn char_set(self, ascii: bool) -> CharSet {
+        match (self, ascii) {
+            (Shorthand::Digit, true) => CharSet::from_ranges([('0', '9')]),
+            (Shorthand::Digit, false) => unicode::digit().clone(),
+            (Shorthand::Word, true) => {
+                CharSet::from_ranges([('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')])
+            }
+            (Shorthand::Word, false) => unicode::word().clone(),
+            (Shorthand::Whitespace, true) => CharSet::from_ranges([('\t', '\r'), (' ', ' ')]),
+            // Same as char::is_whitespace
+            (Shorthand::Whitespace, false) => CharSet::from_ranges([
+                ('\t', '\r'),
+                (' ', ' '),
+                ('\u{85}', '\u{85}'),
+                ('\u{A0}', '\u{A0}'),
+                ('\u{1680}', '\u{1680}'),
+                ('\u{2000}', '\u{200A}'),
+                ('\u{2028}', '\u{2029}'),
+                ('\u{202F}', '\u{202F}'),
+                ('\u{205F}', '\u{205F}'),
+                ('\u{3000}', '\u{3000}'),
+            ]),
+        }
+    }
+}
+
+/// The `[:name:]` classes allowed inside brackets, using the POSIX
+/// definitions for the C locale
+pub fn posix_class(name: &str) -> Option<CharSet> {
+    let ranges: &[(char, char)] = match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "word" => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    };
+    Some(CharSet::from_ranges(ranges.iter().copied()))
+}
+
+/// Simple case folding: map a char to the representative of the set of
+/// chars that are equal ignoring case
+pub fn fold_case(ch: char) -> char {
+    // Dotless i upper-cases to I but isn't case-equal to i
+    if ch == 'ı' {
+        return ch;
+    }
+
+    let upper = single_char(ch.to_uppercase()).unwrap_or(ch);
+    single_char(upper.to_lowercase()).unwrap_or(ch)
+}
+
+/// Every char whose case-folded form differs from itself, paired with that
+/// form. Nothing above U+1E943 has a case mapping, so the scan stops there
+fn case_fold_pairs() -> &'static [(char, char)] {
+    static PAIRS: OnceLock<Vec<(char, char)>> = OnceLock::new();
+    PAIRS.get_or_init(|| {
+        ('\0'..='\u{1E943}')
+            .map(|ch| (ch, fold_case(ch)))
+            .filter(|&(ch, folded)| ch != folded)
+            .collect()
+    })
+}
+
+/// The only char of a case mapping, or `None` for multi-char mappings like ß
+fn single_char(mut mapping: impl Iterator<Item = char>) -> Option<char> {
+    match (mapping.next(), mapping.next()) {
+        (Some(ch), None) => Some(ch),
+        _ => None,
+    }
+}
+
+/// ASCII word characters, which is all `\w` matches in ASCII mode
+pub fn is_word_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
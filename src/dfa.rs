@@ -0,0 +1,246 @@
+//! Lazily built DFA for yes/no matching
+//!
+//! States are sets of NFA instructions from a compiled `Program`, built on
+//! demand as bytes arrive and cached with a transition for every byte value.
+//! A state also records what assertions need to know about the previous
+//! char, plus the bytes of a char that hasn't been fully read yet, so the
+//! DFA runs directly over UTF-8 without decoding the line up front. The
+//! cache is bounded; when it keeps filling up during one search the DFA
+//! gives up and the caller falls back to the Pike VM from then on.
+
+use std::collections::HashMap;
+
+use crate::charset::Shorthand;
+use crate::pikevm::Program;
+
+/// Most states kept at once, each costing a 256-entry transition table
+const MAX_STATES: usize = 2048;
+
+/// Cache clears tolerated in a single search before giving up for good
+const MAX_CLEARS: usize = 3;
+
+type StateId = usize;
+
+/// Transition not computed yet
+const UNKNOWN: StateId = usize::MAX;
+/// The pattern has matched, whatever follows
+const MATCH: StateId = usize::MAX - 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StateKey {
+    kernel: Vec<usize>, // Instructions to resume from, before epsilon closure
+    at_start: bool,     // No char has been read yet
+    prev_word: bool,    // The previous char was a word char
+    pending: Vec<u8>,   // Leading bytes of a partially read char
+}
+
+#[derive(Debug)]
+struct State {
+    key: StateKey,
+    next: Box<[StateId; 256]>,
+    // Whether the input matches if it ends with each byte
+    last: Box<[Option<bool>; 256]>,
+}
+
+/// Where a byte leads from some state
+enum Next {
+    Match,
+    State(StateKey),
+}
+
+#[derive(Debug)]
+pub struct Dfa {
+    states: Vec<State>,
+    ids: HashMap<StateKey, StateId>,
+    anchored: bool,
+    ascii: bool,
+    // Set once the cache has thrashed, since later lines will likely too
+    gave_up: bool,
+}
+
+impl Dfa {
+    pub fn new(anchored: bool, ascii: bool) -> Self {
+        Self {
+            states: Vec::new(),
+            ids: HashMap::new(),
+            anchored,
+            ascii,
+            gave_up: false,
+        }
+    }
+
+    /// Check if `program` matches anywhere in `input`, or return `None` if
+    /// the state cache thrashed and the caller should use the Pike VM
+    pub fn is_match(&mut self, program: &Program, input: &[u8]) -> Option<bool> {
+        if self.gave_up {
+            return None;
+        }
+        let mut clears = 0;
+        let mut state = self.add_state(self.start_key(), &mut clears)?;
+
+        for (i, &byte) in input.iter().enumerate() {
+            // The last char decides whether `$` may match before a final
+            // newline, so its transition isn't shared with other positions
+            if i + 1 == input.len() {
+                if let Some(matched) = self.states[state].last[byte as usize] {
+                    return Some(matched);
+                }
+                let key = self.states[state].key.clone();
+                let matched = match self.next_key(program, &key, byte, true) {
+                    Next::Match => true,
+                    Next::State(key) => self.matches_at_end(program, &key),
+                };
+                self.states[state].last[byte as usize] = Some(matched);
+                return Some(matched);
+            }
+
+            let mut next = self.states[state].next[byte as usize];
+            if next == UNKNOWN {
+                let key = self.states[state].key.clone();
+                next = match self.next_key(program, &key, byte, false) {
+                    Next::Match => MATCH,
+                    Next::State(next_key) => {
+                        let cleared = self.states.len() >= MAX_STATES;
+                        let next = self.add_state(next_key, &mut clears)?;
+                        // The current state is gone if the cache was cleared
+                        if !cleared {
+                            self.states[state].next[byte as usize] = next;
+                        }
+                        next
+                    }
+                };
+            }
+            if next == MATCH {
+                return Some(true);
+            }
+            state = next;
+
+            let key = &self.states[state].key;
+            if key.kernel.is_empty() && key.pending.is_empty() {
+                return Some(false);
+            }
+        }
+
+        let key = self.states[state].key.clone();
+        Some(self.matches_at_end(program, &key))
+    }
+
+    fn start_key(&self) -> StateKey {
+        StateKey {
+            kernel: vec![0],
+            at_start: true,
+            prev_word: false,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Look up or create the state for `key`, clearing the cache when it's
+    /// full. Returns `None` once the cache has been cleared too often.
+    fn add_state(&mut self, key: StateKey, clears: &mut usize) -> Option<StateId> {
+        if let Some(&id) = self.ids.get(&key) {
+            return Some(id);
+        }
+        if self.states.len() >= MAX_STATES {
+            *clears += 1;
+            if *clears > MAX_CLEARS {
+                self.gave_up = true;
+                return None;
+            }
+            self.states.clear();
+            self.ids.clear();
+        }
+
+        let id = self.states.len();
+        self.ids.insert(key.clone(), id);
+        self.states.push(State {
+            key,
+            next: Box::new([UNKNOWN; 256]),
+            last: Box::new([None; 256]),
+        });
+        Some(id)
+    }
+
+    /// Feed one byte to the state described by `key`. Invalid UTF-8 is
+    /// read as U+FFFD, one per maximal invalid sequence, like
+    /// `<[u8]>::utf8_chunks`.
+    fn next_key(&self, program: &Program, key: &StateKey, byte: u8, is_last: bool) -> Next {
+        let mut key = key.clone();
+        let mut bytes = std::mem::take(&mut key.pending);
+        bytes.push(byte);
+
+        loop {
+            let (ch, rest) = match std::str::from_utf8(&bytes) {
+                Ok(text) => (
+                    text.chars().next().unwrap_or(char::REPLACEMENT_CHARACTER),
+                    &[][..],
+                ),
+                Err(err) => match err.error_len() {
+                    // Wait for the rest of the char
+                    None if !is_last => {
+                        key.pending = bytes;
+                        return Next::State(key);
+                    }
+                    None => (char::REPLACEMENT_CHARACTER, &[][..]),
+                    // The bytes after an invalid sequence start afresh
+                    Some(len) => (char::REPLACEMENT_CHARACTER, &bytes[len..]),
+                },
+            };
+
+            match self.next_char(program, &key, ch, is_last && rest.is_empty()) {
+                Next::Match => return Next::Match,
+                Next::State(next) if rest.is_empty() => return Next::State(next),
+                Next::State(next) => {
+                    key = next;
+                    bytes = rest.to_vec();
+                }
+            }
+        }
+    }
+
+    /// Feed one whole char to the state described by `key`
+    fn next_char(&self, program: &Program, key: &StateKey, ch: char, is_last: bool) -> Next {
+        let mut window = self.window(key);
+        let pos = window.len();
+        window.push(ch);
+        if !is_last {
+            // Any char will do; it only shows that the input goes on
+            window.push(' ');
+        }
+
+        let (pcs, matched) = program.closure(&key.kernel, &window, pos, self.ascii);
+        if matched {
+            return Next::Match;
+        }
+
+        let mut kernel = program.step(&pcs, ch, self.ascii);
+        if !self.anchored && kernel.first() != Some(&0) {
+            // Start another attempt at the next position
+            kernel.insert(0, 0);
+        }
+
+        Next::State(StateKey {
+            kernel,
+            at_start: false,
+            prev_word: Shorthand::Word.matches(ch, self.ascii),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Whether the pattern matches once the input has run out
+    fn matches_at_end(&self, program: &Program, key: &StateKey) -> bool {
+        let window = self.window(key);
+        program
+            .closure(&key.kernel, &window, window.len(), self.ascii)
+            .1
+    }
+
+    /// Stand-in for the text before the current position: assertions only
+    /// care whether there is a previous char and whether it's a word char
+    fn window(&self, key: &StateKey) -> Vec<char> {
+        match (key.at_start, key.prev_word) {
+            (true, _) => Vec::new(),
+            (false, true) => vec!['a'],
+            (false, false) => vec![' '],
+        }
+    }
+}
@@ -0,0 +1,819 @@
+//! A regular expression engine with a grep-style CLI on top
+//!
+//! Patterns compile to a Pike VM, with a lazy DFA for yes/no questions, and
+//! fall back to a bounded backtracker for backreferences and lookaround.
+//! Haystacks are bytes: invalid UTF-8 reads as U+FFFD, and every offset
+//! reported is a byte offset into the haystack.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use charset::is_word_char;
+use matcher::{Decoded, Matcher};
+
+mod backtrack;
+mod charset;
+mod dfa;
+mod matcher;
+mod parser;
+mod pikevm;
+mod prefilter;
+mod token;
+mod unicode;
+#[rustfmt::skip]
+mod unicode_tables;
+
+pub use matcher::MatchOptions;
+pub use parser::{ParseError, ParseErrorKind};
+
+/// Why a pattern couldn't be compiled or a search couldn't finish
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("pattern too complex: gave up after {0} backtracking steps")]
+    TooComplex(usize),
+}
+
+/// A compiled regular expression
+pub struct Regex {
+    pattern: String,
+    matcher: Matcher,
+}
+
+impl Regex {
+    /// Compile `pattern` with the default options
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        Self::with_options(pattern, &MatchOptions::default())
+    }
+
+    pub fn with_options(pattern: &str, options: &MatchOptions) -> Result<Self, Error> {
+        Ok(Self {
+            pattern: pattern.to_string(),
+            matcher: Matcher::new(pattern, options)?,
+        })
+    }
+
+    /// The pattern this regex was compiled from
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Number of capture groups, counting the whole match as group 0
+    pub fn captures_len(&self) -> usize {
+        self.matcher.groups() + 1
+    }
+
+    /// Index of the group named `name` with `(?<name>...)` or
+    /// `(?P<name>...)`
+    pub fn capture_index(&self, name: &str) -> Option<usize> {
+        self.matcher.group_names().get(name).copied()
+    }
+
+    /// Check if the pattern matches anywhere in `haystack`. A search that
+    /// runs out of backtracking steps counts as no match; use
+    /// `try_is_match` to tell the two apart.
+    pub fn is_match(&self, haystack: &(impl AsRef<[u8]> + ?Sized)) -> bool {
+        self.try_is_match(haystack).unwrap_or(false)
+    }
+
+    pub fn try_is_match(&self, haystack: &(impl AsRef<[u8]> + ?Sized)) -> Result<bool, Error> {
+        self.matcher.is_match(haystack.as_ref())
+    }
+
+    /// The leftmost-first match in `haystack`. Like `is_match`, this reports
+    /// no match if the search runs out of backtracking steps.
+    pub fn find<'h>(&self, haystack: &'h (impl AsRef<[u8]> + ?Sized)) -> Option<Match<'h>> {
+        self.try_find(haystack).unwrap_or(None)
+    }
+
+    pub fn try_find<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+    ) -> Result<Option<Match<'h>>, Error> {
+        Ok(self
+            .try_captures(haystack)?
+            .and_then(|captures| captures.get(0)))
+    }
+
+    /// The leftmost-first match in `haystack` along with the span of every
+    /// capture group
+    pub fn captures<'h>(&self, haystack: &'h (impl AsRef<[u8]> + ?Sized)) -> Option<Captures<'h>> {
+        self.try_captures(haystack).unwrap_or(None)
+    }
+
+    pub fn try_captures<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+    ) -> Result<Option<Captures<'h>>, Error> {
+        let haystack = haystack.as_ref();
+        let decoded = Decoded::new(haystack);
+        Ok(self
+            .captures_at(haystack, &decoded, 0)?
+            .map(|(captures, _)| captures))
+    }
+
+    /// Every non-overlapping match in `haystack`, from left to right. An
+    /// empty match right after the previous match is skipped, so `a*` finds
+    /// `aa` and then the empty match at the end in `aab`. Iteration stops
+    /// early if a search runs out of backtracking steps.
+    pub fn find_iter<'r, 'h>(
+        &'r self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+    ) -> Matches<'r, 'h> {
+        Matches(self.try_captures_iter(haystack))
+    }
+
+    /// Like `find_iter`, with the capture groups of each match
+    pub fn captures_iter<'r, 'h>(
+        &'r self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+    ) -> CaptureMatches<'r, 'h> {
+        CaptureMatches(self.try_captures_iter(haystack))
+    }
+
+    /// Like `captures_iter`, but reports a search that runs out of
+    /// backtracking steps as a final `Err`
+    pub fn try_captures_iter<'r, 'h>(
+        &'r self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+    ) -> TryCaptureMatches<'r, 'h> {
+        let haystack = haystack.as_ref();
+        TryCaptureMatches {
+            regex: self,
+            haystack,
+            decoded: Decoded::new(haystack),
+            start: Some(0),
+            last_end: None,
+        }
+    }
+
+    /// Replace the leftmost-first match in `haystack` with `template`,
+    /// expanded as by `Captures::expand`. The haystack is returned borrowed
+    /// if nothing matched, including when the search ran out of
+    /// backtracking steps.
+    pub fn replace<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+        template: &str,
+    ) -> Cow<'h, [u8]> {
+        let haystack = haystack.as_ref();
+        self.try_replace(haystack, template)
+            .unwrap_or(Cow::Borrowed(haystack))
+    }
+
+    pub fn try_replace<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+        template: &str,
+    ) -> Result<Cow<'h, [u8]>, Error> {
+        self.replacen(haystack.as_ref(), 1, template)
+    }
+
+    /// Like `replace`, for every match `find_iter` would report
+    pub fn replace_all<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+        template: &str,
+    ) -> Cow<'h, [u8]> {
+        let haystack = haystack.as_ref();
+        self.try_replace_all(haystack, template)
+            .unwrap_or(Cow::Borrowed(haystack))
+    }
+
+    pub fn try_replace_all<'h>(
+        &self,
+        haystack: &'h (impl AsRef<[u8]> + ?Sized),
+        template: &str,
+    ) -> Result<Cow<'h, [u8]>, Error> {
+        self.replacen(haystack.as_ref(), usize::MAX, template)
+    }
+
+    /// Replace the first `limit` matches
+    fn replacen<'h>(
+        &self,
+        haystack: &'h [u8],
+        limit: usize,
+        template: &str,
+    ) -> Result<Cow<'h, [u8]>, Error> {
+        let mut replaced = Vec::new();
+        let mut matched = false;
+        let mut last = 0;
+
+        for captures in self.try_captures_iter(haystack).take(limit) {
+            let captures = captures?;
+            let found = captures.get(0).expect("group 0 always takes part");
+            replaced.extend_from_slice(&haystack[last..found.start()]);
+            captures.expand(template, &mut replaced);
+            last = found.end();
+            matched = true;
+        }
+
+        if !matched {
+            return Ok(Cow::Borrowed(haystack));
+        }
+        replaced.extend_from_slice(&haystack[last..]);
+        Ok(Cow::Owned(replaced))
+    }
+
+    /// Search from char `start` of `decoded`, returning the captures with
+    /// byte offsets and the char position where the match ends
+    fn captures_at<'h>(
+        &self,
+        haystack: &'h [u8],
+        decoded: &Decoded,
+        start: usize,
+    ) -> Result<Option<(Captures<'h>, usize)>, Error> {
+        let Some(slots) = self.matcher.search(haystack, decoded, start)? else {
+            return Ok(None);
+        };
+
+        let end = slots[1].expect("a match always has an end");
+        let captures = Captures {
+            haystack,
+            slots: slots
+                .into_iter()
+                .map(|slot| slot.map(|pos| decoded.offsets[pos]))
+                .collect(),
+            names: self.matcher.group_names().clone(),
+        };
+        Ok(Some((captures, end)))
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Regex").field(&self.pattern).finish()
+    }
+}
+
+/// Iterator over successive matches, returned by `Regex::try_captures_iter`
+#[derive(Debug)]
+pub struct TryCaptureMatches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h [u8],
+    decoded: Decoded,
+    // Char position to search from, or `None` once iteration is over
+    start: Option<usize>,
+    // Char position where the previous match ended
+    last_end: Option<usize>,
+}
+
+impl<'h> Iterator for TryCaptureMatches<'_, 'h> {
+    type Item = Result<Captures<'h>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.start.take()?;
+            if start > self.decoded.chars.len() {
+                return None;
+            }
+
+            let (captures, end) = match self.regex.captures_at(self.haystack, &self.decoded, start)
+            {
+                Ok(Some(found)) => found,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+
+            // Step past an empty match so the next search can't find it again
+            let empty = captures.get(0).is_some_and(|m| m.is_empty());
+            self.start = Some(if empty { end + 1 } else { end });
+
+            if empty && self.last_end == Some(end) {
+                continue;
+            }
+            self.last_end = Some(end);
+            return Some(Ok(captures));
+        }
+    }
+}
+
+/// Iterator over successive matches, returned by `Regex::captures_iter`
+#[derive(Debug)]
+pub struct CaptureMatches<'r, 'h>(TryCaptureMatches<'r, 'h>);
+
+impl<'h> Iterator for CaptureMatches<'_, 'h> {
+    type Item = Captures<'h>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()?.ok()
+    }
+}
+
+/// Iterator over successive match spans, returned by `Regex::find_iter`
+#[derive(Debug)]
+pub struct Matches<'r, 'h>(TryCaptureMatches<'r, 'h>);
+
+impl<'h> Iterator for Matches<'_, 'h> {
+    type Item = Match<'h>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()?.ok()?.get(0)
+    }
+}
+
+/// Where a match or capture group was found, as byte offsets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'h> {
+    haystack: &'h [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The matched bytes
+    pub fn as_bytes(&self) -> &'h [u8] {
+        &self.haystack[self.range()]
+    }
+
+    /// The matched text, or `None` if it isn't valid UTF-8
+    pub fn as_str(&self) -> Option<&'h str> {
+        std::str::from_utf8(self.as_bytes()).ok()
+    }
+}
+
+/// The groups of one match. Group 0 is the whole match; groups that didn't
+/// take part in the match have no span.
+#[derive(Debug, Clone)]
+pub struct Captures<'h> {
+    haystack: &'h [u8],
+    // Slots 2n and 2n+1 hold the byte span of group n
+    slots: Vec<Option<usize>>,
+    names: Arc<HashMap<String, usize>>,
+}
+
+impl<'h> Captures<'h> {
+    pub fn get(&self, group: usize) -> Option<Match<'h>> {
+        // Group numbers come from user input, like `$n` in a template
+        let slot = group.checked_mul(2)?;
+        let start = (*self.slots.get(slot)?)?;
+        let end = (*self.slots.get(slot + 1)?)?;
+        Some(Match {
+            haystack: self.haystack,
+            start,
+            end,
+        })
+    }
+
+    /// The group named `name` with `(?<name>...)` or `(?P<name>...)`
+    pub fn name(&self, name: &str) -> Option<Match<'h>> {
+        self.get(*self.names.get(name)?)
+    }
+
+    /// Number of groups, counting the whole match as group 0
+    pub fn len(&self) -> usize {
+        self.slots.len() / 2
+    }
+
+    /// Always false, since group 0 is always there
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Append `template` to `dst`, with `$n` or `${n}` replaced by group n,
+    /// `$name` or `${name}` by the named group and `$$` by a single `$`.
+    /// A reference without braces takes as many digits, or as many word
+    /// chars after a leading non-digit, as it can, so `$1a` is group 1
+    /// followed by `a`. Groups that didn't take part expand to nothing, and
+    /// a `$` that doesn't start a reference is copied as is.
+    pub fn expand(&self, template: &str, dst: &mut Vec<u8>) {
+        let mut rest = template;
+
+        while let Some(dollar) = rest.find('$') {
+            dst.extend_from_slice(&rest.as_bytes()[..dollar]);
+            rest = &rest[dollar + 1..];
+
+            if let Some(after) = rest.strip_prefix('$') {
+                dst.push(b'$');
+                rest = after;
+                continue;
+            }
+
+            let (reference, after) = match rest.strip_prefix('{') {
+                Some(braced) => braced.split_once('}').unwrap_or(("", rest)),
+                None => {
+                    let len = if rest.starts_with(|ch: char| ch.is_ascii_digit()) {
+                        rest.find(|ch: char| !ch.is_ascii_digit())
+                    } else {
+                        rest.find(|ch: char| !is_word_char(ch))
+                    };
+                    rest.split_at(len.unwrap_or(rest.len()))
+                }
+            };
+            if reference.is_empty() {
+                dst.push(b'$');
+                continue;
+            }
+
+            let group = match reference.parse::<usize>() {
+                Ok(group) => self.get(group),
+                Err(_) => self.name(reference),
+            };
+            if let Some(found) = group {
+                dst.extend_from_slice(found.as_bytes());
+            }
+            rest = after;
+        }
+
+        dst.extend_from_slice(rest.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, haystack: &str) -> Option<Range<usize>> {
+        Regex::new(pattern)
+            .unwrap()
+            .find(haystack)
+            .map(|m| m.range())
+    }
+
+    fn groups(pattern: &str, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+        let regex = Regex::new(pattern).unwrap();
+        let captures = regex.captures(haystack)?;
+        Some(
+            (0..captures.len())
+                .map(|group| captures.get(group).map(|m| m.range()))
+                .collect(),
+        )
+    }
+
+    fn find_all(pattern: &str, haystack: &str) -> Vec<Range<usize>> {
+        let regex = Regex::new(pattern).unwrap();
+        regex.find_iter(haystack).map(|m| m.range()).collect()
+    }
+
+    fn parse_error(pattern: &str) -> (usize, ParseErrorKind) {
+        match Regex::new(pattern) {
+            Err(Error::Parse(err)) => (err.offset, err.kind),
+            Err(err) => panic!("{:?} failed with {}", pattern, err),
+            Ok(_) => panic!("{:?} compiled", pattern),
+        }
+    }
+
+    #[test]
+    fn malformed_repetitions_are_errors() {
+        assert_eq!(
+            parse_error("ab{2,1}"),
+            (2, ParseErrorKind::ReversedBounds(2, 1))
+        );
+        assert_eq!(
+            parse_error("x{99999999999999999999}"),
+            (
+                1,
+                ParseErrorKind::RepetitionTooLarge("99999999999999999999".to_string())
+            )
+        );
+        assert_eq!(
+            parse_error("x{1,99999999999999999999}"),
+            (
+                1,
+                ParseErrorKind::RepetitionTooLarge("99999999999999999999".to_string())
+            )
+        );
+        assert_eq!(
+            parse_error("*a"),
+            (0, ParseErrorKind::DanglingQuantifier('*'))
+        );
+
+        // Braces that don't hold a count are literal
+        assert_eq!(find("x{a}", "x{a}"), Some(0..4));
+        assert_eq!(find("x{,3}", "x{,3}"), Some(0..5));
+        assert_eq!(find("x{2,}", "xxx"), Some(0..3));
+    }
+
+    #[test]
+    fn references_to_missing_groups_are_errors() {
+        assert_eq!(parse_error(r"(a)\2"), (3, ParseErrorKind::UnknownGroup(2)));
+        assert_eq!(
+            parse_error(r"(?<x>a)\k<y>"),
+            (7, ParseErrorKind::UnknownGroupName("y".to_string()))
+        );
+        // A reference may come before its group
+        assert_eq!(find(r"(?:\2|(b)(a))+", "baa"), Some(0..3));
+    }
+
+    #[test]
+    fn find_reports_byte_offsets() {
+        assert_eq!(find("b+", "abbbc"), Some(1..4));
+        assert_eq!(find("é+", "caféé!"), Some(3..7));
+        assert_eq!(find(r"\d", "no digits"), None);
+        assert_eq!(find("", "abc"), Some(0..0));
+        assert_eq!(find("c$", "abc"), Some(2..3));
+
+        let regex = Regex::new("ab").unwrap();
+        assert_eq!(regex.find(b"\xffab").map(|m| m.range()), Some(1..3));
+    }
+
+    #[test]
+    fn class_set_operations() {
+        assert_eq!(find(r"[\w&&[^\d]]+", "1a_2"), Some(1..3));
+        assert_eq!(find(r"[a-z--[aeiou]]+", "aebcd"), Some(2..5));
+        assert_eq!(find(r"[[a-f][0-9]]+", "xa9fg"), Some(1..4));
+        assert_eq!(find(r"[a-c~~b-d]+", "bcad"), Some(2..4));
+        // Operators apply left to right, and '^' negates the result
+        assert_eq!(find(r"[a-z--aeiou&&a-f]+", "aebcdfg"), Some(2..6));
+        assert_eq!(find(r"[^\w--\d]", "ab1"), Some(2..3));
+        // A single '-' or '&' is still a plain char
+        assert_eq!(find(r"[a-]+", "x-a"), Some(1..3));
+        assert_eq!(find(r"[a&]+", "x&a"), Some(1..3));
+    }
+
+    #[test]
+    fn leading_bracket_in_a_class_is_literal() {
+        assert_eq!(find("[]a]", "x]"), Some(1..2));
+        assert_eq!(find("[^]a]", "]ab"), Some(2..3));
+        assert_eq!(find("[]-a]", "^"), Some(0..1));
+        assert_eq!(find("[[]", "a["), Some(1..2));
+        assert!(matches!(
+            Regex::new("[]"),
+            Err(Error::Parse(ParseError {
+                kind: ParseErrorKind::UnclosedClass,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn find_is_leftmost_first() {
+        assert_eq!(find("a|ab", "xab"), Some(1..2));
+        assert_eq!(find("ab|a", "xab"), Some(1..3));
+        assert_eq!(find("a+?", "aaa"), Some(0..1));
+        assert_eq!(find("(?=a)a{3}", "baaa"), Some(1..4));
+    }
+
+    #[test]
+    fn captures_report_group_spans() {
+        assert_eq!(
+            groups(r"(\w+)@(\w+)\.com", "mail bob@example.com now"),
+            Some(vec![Some(5..20), Some(5..8), Some(9..16)])
+        );
+        assert_eq!(
+            groups("(a)|(b)", "b"),
+            Some(vec![Some(0..1), None, Some(0..1)])
+        );
+        assert_eq!(
+            groups(r"(\w)\1", "abccd"),
+            Some(vec![Some(2..4), Some(2..3)])
+        );
+        assert_eq!(
+            groups(r"(?<=\$)(\d)+", "cost $42"),
+            Some(vec![Some(6..8), Some(7..8)])
+        );
+
+        let regex = Regex::new(r"(?<year>\d{4})-(?<month>\d\d)").unwrap();
+        let captures = regex.captures("on 2024-05").unwrap();
+        assert_eq!(captures.name("year").map(|m| m.range()), Some(3..7));
+        assert_eq!(captures.name("month").map(|m| m.range()), Some(8..10));
+        assert_eq!(captures.name("day"), None);
+        assert_eq!(captures.get(usize::MAX), None);
+    }
+
+    #[test]
+    fn captures_keep_an_empty_final_iteration() {
+        assert_eq!(groups("(a*)*b", "b"), Some(vec![Some(0..1), Some(0..0)]));
+        assert_eq!(groups("(a*)+b", "aab"), Some(vec![Some(0..3), Some(2..2)]));
+        assert_eq!(groups(r"(a*)?b\1", "b"), Some(vec![Some(0..1), Some(0..0)]));
+        assert_eq!(groups(r"(a*)*b\1", "b"), Some(vec![Some(0..1), Some(0..0)]));
+    }
+
+    #[test]
+    fn backreference_inside_its_group_sees_the_previous_capture() {
+        assert_eq!(
+            groups(r"(?:(\1x|a).)+", "abab"),
+            Some(vec![Some(0..4), Some(2..3)])
+        );
+        assert_eq!(
+            groups(r"(?:(\1b|a))+", "aabab"),
+            Some(vec![Some(0..4), Some(3..4)])
+        );
+        assert_eq!(groups(r"(a\1)", "aa"), None);
+    }
+
+    #[test]
+    fn inline_ignore_case_is_scoped() {
+        assert_eq!(find("a(?i)b", "AB aB"), Some(3..5));
+        assert_eq!(find("(?i:a)b", "AB Ab"), Some(3..5));
+        assert_eq!(find("(?i:a(?-i:b)c)", "ABC AbC"), Some(4..7));
+        assert_eq!(find("(?i)a(?-i)b", "AB Ab"), Some(3..5));
+        // A bare flag ends with its group but spans alternatives
+        assert_eq!(find("(a(?i)b)c", "aBC aBc"), Some(4..7));
+        assert_eq!(find("x(?i)a|b", "B"), Some(0..1));
+    }
+
+    #[test]
+    fn ignore_case_uses_simple_case_folding() {
+        assert_eq!(find("(?i)Σ", "ς"), Some(0..2));
+        assert_eq!(find("(?i)K", "\u{212a}"), Some(0..3));
+        assert_eq!(find("(?i)[a-c]+", "xBCa"), Some(1..4));
+        assert_eq!(find("(?i)[^a]", "A b"), Some(1..2));
+        assert_eq!(find(r"(?i)(a)\1", "aA"), Some(0..2));
+
+        let options = MatchOptions {
+            ignore_case: true,
+            ..MatchOptions::default()
+        };
+        let regex = Regex::with_options("straße", &options).unwrap();
+        assert_eq!(regex.find("STRAẞE").map(|m| m.range()), Some(0..8));
+    }
+
+    #[test]
+    fn escapes_match_the_chars_they_name() {
+        assert_eq!(find(r"a\tb", "a\tb"), Some(0..3));
+        assert_eq!(find(r"\n", "a\nb"), Some(1..2));
+        assert_eq!(find(r"\x41é", "xAé"), Some(1..4));
+        assert_eq!(find(r"\x{1F600}", "😀"), Some(0..4));
+        assert_eq!(find(r"\u{e9}", "é"), Some(0..2));
+        assert_eq!(find(r"\012", "a\n"), Some(1..2));
+        assert_eq!(find(r"\0", "a\0"), Some(1..2));
+        assert_eq!(find(r"[\x41-\x43]+", "xABCD"), Some(1..4));
+    }
+
+    #[test]
+    fn quoting_makes_metacharacters_literal() {
+        assert_eq!(find(r"\Qa.b\E", "axb a.b"), Some(4..7));
+        assert_eq!(find(r"\Q(a|b)*", "(a|b)*"), Some(0..6));
+        assert_eq!(find(r"\Qab\E+", "abbb"), Some(0..4));
+        assert_eq!(find(r"[\Q^]\E]+", "a]^"), Some(1..3));
+    }
+
+    #[test]
+    fn malformed_escapes_are_errors() {
+        assert_eq!(
+            parse_error(r"ab\q"),
+            (2, ParseErrorKind::UnknownEscape('q'))
+        );
+        assert_eq!(
+            parse_error(r"[\q]"),
+            (1, ParseErrorKind::UnknownEscape('q'))
+        );
+        assert_eq!(parse_error(r"a\x4"), (1, ParseErrorKind::InvalidCodePoint));
+        assert_eq!(
+            parse_error(r"\x{110000}"),
+            (0, ParseErrorKind::InvalidCodePoint)
+        );
+        assert_eq!(parse_error(r"\u{}"), (0, ParseErrorKind::InvalidCodePoint));
+        assert_eq!(parse_error("a\\"), (1, ParseErrorKind::TrailingBackslash));
+    }
+
+    #[test]
+    fn find_iter_skips_empty_matches_after_a_match() {
+        assert_eq!(find_all(r"\d+", "1 22 333"), vec![0..1, 2..4, 5..8]);
+        assert_eq!(find_all("a*", "aab"), vec![0..2, 3..3]);
+        assert_eq!(find_all("", "é"), vec![0..0, 2..2]);
+        assert_eq!(find_all(r"(\w)\1", "aabbcd"), vec![0..2, 2..4]);
+        assert_eq!(find_all("x", "abc"), Vec::<Range<usize>>::new());
+    }
+
+    #[test]
+    fn step_limit_applies_to_each_start() {
+        let options = MatchOptions {
+            step_limit: 1_000,
+            ..MatchOptions::default()
+        };
+        let regex = Regex::with_options(r"(\w+) \1x", &options).unwrap();
+        let line = format!("{}zz zzx", "abcdefgh ".repeat(1_000));
+        assert_eq!(regex.try_is_match(&line), Ok(true));
+
+        let regex = Regex::with_options(r"((a|a)*)\2z", &options).unwrap();
+        let line = format!("{}bz", "a".repeat(40));
+        assert_eq!(regex.try_is_match(&line), Err(Error::TooComplex(1_000)));
+    }
+
+    const PATTERNS: &[&str] = &[
+        "a",
+        "ab|a",
+        "a|ab",
+        "a*",
+        "a+?b",
+        "(a|b)*c",
+        "(a*)*",
+        "(a*)+b",
+        "(a*)?b",
+        "(a|ab)(c|bcd)(d*)",
+        "(a?)((ab)?)(b?)",
+        "(a{0,2}?)*b",
+        "(ab|a)+?b",
+        "([^a]*?)?\\w",
+        "(\\w+)\\s(\\w+)",
+        "^a*$",
+        "^(a|b)+$",
+        "\\bab\\b",
+        "(x?)*y",
+        "((a)|b)+",
+        "(?:a|(b))*c",
+        "[a-c]{2,3}",
+        "(a{2})*",
+        "é+a",
+        "(?i)AB",
+        "(?<n>a+)b",
+        "(c*(?:b*)*)*",
+        "((.?)+|)*c",
+        "((?:c*)*|)*",
+        "(((b){0,2})+)*|b",
+    ];
+
+    const HAYSTACKS: &[&str] = &[
+        "", "a", "b", "ab", "aab", "abcd", "b a", "xyz", "aaaa", "babab", "ééa", "ab ab", "xxy",
+        "abcabc", "c", "ac", "cccc", "cabba",
+    ];
+
+    #[test]
+    fn pike_vm_agrees_with_the_backtracker() {
+        for pattern in PATTERNS {
+            // An empty lookahead changes nothing but can only be backtracked
+            let backtracked = format!("(?=){}", pattern);
+            for haystack in HAYSTACKS {
+                assert_eq!(
+                    groups(pattern, haystack),
+                    groups(&backtracked, haystack),
+                    "{:?} on {:?}",
+                    pattern,
+                    haystack
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_dfa_agrees_with_the_pike_vm() {
+        for pattern in PATTERNS {
+            let regex = Regex::new(pattern).unwrap();
+            for haystack in HAYSTACKS {
+                assert_eq!(
+                    regex.is_match(haystack),
+                    regex.find(haystack).is_some(),
+                    "{:?} on {:?}",
+                    pattern,
+                    haystack
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_dfa_survives_a_full_cache() {
+        // Needs a state per window of the last 12 chars, far more than fit
+        let regex = Regex::new("a[ab]{12}c").unwrap();
+        let mut seed = 1u32;
+        for _ in 0..200 {
+            let line: String = (0..300)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    match (seed >> 16) % 40 {
+                        0 => 'c',
+                        n if n % 2 == 0 => 'a',
+                        _ => 'b',
+                    }
+                })
+                .collect();
+            assert_eq!(
+                regex.is_match(&line),
+                regex.find(&line).is_some(),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn backtracker_handles_long_lines() {
+        let line = format!("password=abc{}", "x".repeat(200_000));
+        let regex = Regex::new("password=(?!REDACTED).*$").unwrap();
+        let found = regex.try_find(&line).unwrap().map(|m| m.range());
+        assert_eq!(found, Some(0..line.len()));
+
+        let line = "a".repeat(200_000);
+        let regex = Regex::new(r"(a)\1.*").unwrap();
+        let found = regex.try_find(&line).unwrap().map(|m| m.range());
+        assert_eq!(found, Some(0..line.len()));
+    }
+}
@@ -1,719 +1,480 @@
+use std::borrow::Cow;
 use std::env;
-use std::io;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::process;
 
-/// Represents different types of regex tokens
-#[derive(Debug, Clone)]
-enum Token {
-    Char(char),                   // Literal character
-    Dot,                          // . matches any character
-    Digit,                        // \d matches digits
-    Word,                         // \w matches word characters
-    Whitespace,                   // \s matches whitespace
-    CharClass(Vec<char>),         // [abc] character class
-    NegCharClass(Vec<char>),      // [^abc] negated character class
-    Group(Vec<Token>, usize),     // (pattern) with group number
-    Alternative(Vec<Vec<Token>>), // a|b alternatives
-    Plus(Box<Token>),             // a+ one or more
-    Question(Box<Token>),         // a? optional
-    Backreference(usize),         // \1 backreference
-}
+use codecrafters_grep::{Error, MatchOptions, ParseError, Regex};
 
-/// Holds captured groups during matching
-#[derive(Debug, Clone)]
-struct Captures {
-    groups: Vec<String>,
+/// How to treat files that contain NUL bytes, as with GNU grep's
+/// `--binary-files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum BinaryFiles {
+    #[default]
+    Binary, // Report that the file matches instead of printing lines
+    Text,         // Search and print it like any other file
+    WithoutMatch, // Assume it doesn't match
 }
 
-impl Captures {
-    fn new() -> Self {
-        Self { groups: Vec::new() }
-    }
-
-    fn ensure_capacity(&mut self, group_num: usize) {
-        while self.groups.len() < group_num {
-            self.groups.push(String::new());
-        }
-    }
-
-    fn set_group(&mut self, group_num: usize, text: String) {
-        self.ensure_capacity(group_num);
-        self.groups[group_num - 1] = text;
-    }
-
-    fn get_group(&self, group_num: usize) -> Option<&str> {
-        if group_num == 0 || group_num > self.groups.len() {
-            None
-        } else {
-            Some(&self.groups[group_num - 1])
+impl BinaryFiles {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "binary" => Ok(BinaryFiles::Binary),
+            "text" => Ok(BinaryFiles::Text),
+            "without-match" => Ok(BinaryFiles::WithoutMatch),
+            _ => Err(format!("unknown binary-files type '{}'", name)),
         }
     }
 }
 
-/// Main pattern matcher
-struct Matcher {
-    tokens: Vec<Token>,
-    anchored_start: bool,
-    anchored_end: bool,
+/// What to print for each file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Output {
+    #[default]
+    Lines, // Every selected line, or the matches in it with -o
+    Count,             // How many lines were selected
+    FilesWithMatches,  // The file's name if any line was selected
+    FilesWithoutMatch, // The file's name if no line was selected
+    Quiet,             // Nothing; stop at the first selected line
 }
 
-impl Matcher {
-    fn new(pattern: &str) -> Self {
-        let mut parser = Parser::new(pattern);
-        let tokens = parser.parse();
-
-        Self {
-            anchored_start: pattern.starts_with('^'),
-            anchored_end: pattern.ends_with('$'),
-            tokens,
-        }
-    }
-
-    /// Check if the pattern matches the input
-    fn is_match(&self, input: &str) -> bool {
-        let chars: Vec<char> = input.chars().collect();
-
-        if self.anchored_start && self.anchored_end {
-            // Must match entire string
-            let mut captures = Captures::new();
-            self.match_at(&chars, 0, &self.tokens, &mut captures) == Some(chars.len())
-        } else if self.anchored_start {
-            // Must match from beginning
-            let mut captures = Captures::new();
-            self.match_at(&chars, 0, &self.tokens, &mut captures)
-                .is_some()
-        } else if self.anchored_end {
-            // Must match until end
-            for start in 0..=chars.len() {
-                let mut captures = Captures::new();
-                if let Some(end) = self.match_at(&chars, start, &self.tokens, &mut captures) {
-                    if end == chars.len() {
-                        return true;
-                    }
-                }
-            }
-            false
-        } else {
-            // Can match anywhere
-            for start in 0..=chars.len() {
-                let mut captures = Captures::new();
-                if self
-                    .match_at(&chars, start, &self.tokens, &mut captures)
-                    .is_some()
-                {
-                    return true;
-                }
-            }
-            false
-        }
-    }
-
-    /// Try to match tokens starting at a specific position
-    fn match_at(
-        &self,
-        chars: &[char],
-        pos: usize,
-        tokens: &[Token],
-        captures: &mut Captures,
-    ) -> Option<usize> {
-        if tokens.is_empty() {
-            return Some(pos);
-        }
-
-        let token = &tokens[0];
-        let remaining = &tokens[1..];
-
-        match token {
-            Token::Char(ch) => {
-                if pos < chars.len() && chars[pos] == *ch {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
-
-            Token::Dot => {
-                if pos < chars.len() {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
-
-            Token::Digit => {
-                if pos < chars.len() && chars[pos].is_ascii_digit() {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
-
-            Token::Word => {
-                if pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
-
-            Token::Whitespace => {
-                if pos < chars.len() && chars[pos].is_whitespace() {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
+/// Command line options
+#[derive(Default)]
+struct Options {
+    pattern: String,
+    // File names needn't be UTF-8, unlike everything else
+    files: Vec<OsString>,
+    match_options: MatchOptions,
+    binary_files: BinaryFiles,
+    only_matching: bool,
+    invert: bool,
+    output: Output,
+    max_count: Option<usize>,
+    // Group to print instead of the whole match, by number or name
+    capture: Option<String>,
+    // Template to print in place of each match
+    replace: Option<String>,
+    // Rewrite files with the replacements instead of printing, keeping the
+    // originals under this suffix unless it's empty
+    in_place: Option<String>,
+}
 
-            Token::CharClass(allowed) => {
-                if pos < chars.len() && allowed.contains(&chars[pos]) {
-                    self.match_at(chars, pos + 1, remaining, captures)
-                } else {
-                    None
-                }
-            }
+impl Options {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut pattern = None;
+        let mut options_done = false;
 
-            Token::NegCharClass(forbidden) => {
-                if pos < chars.len() && !forbidden.contains(&chars[pos]) {
-                    self.match_at(chars, pos + 1, remaining, captures)
+        while let Some(arg) = args.next() {
+            if options_done || arg == "-" || !arg.as_encoded_bytes().starts_with(b"-") {
+                if pattern.is_none() {
+                    pattern = Some(into_utf8(arg)?);
                 } else {
-                    None
-                }
-            }
-
-            Token::Group(group_tokens, group_num) => {
-                let start_pos = pos;
-                let mut temp_captures = captures.clone();
-
-                if let Some(end_pos) = self.match_at(chars, pos, group_tokens, &mut temp_captures) {
-                    // Capture the matched text
-                    let matched_text: String = chars[start_pos..end_pos].iter().collect();
-                    temp_captures.set_group(*group_num, matched_text);
-
-                    // Continue with remaining tokens
-                    if let Some(final_pos) =
-                        self.match_at(chars, end_pos, remaining, &mut temp_captures)
-                    {
-                        *captures = temp_captures;
-                        return Some(final_pos);
-                    }
-                }
-                None
-            }
-
-            Token::Alternative(alternatives) => {
-                for alt_tokens in alternatives {
-                    let mut temp_captures = captures.clone();
-                    if let Some(end_pos) = self.match_at(chars, pos, alt_tokens, &mut temp_captures)
-                    {
-                        if let Some(final_pos) =
-                            self.match_at(chars, end_pos, remaining, &mut temp_captures)
-                        {
-                            *captures = temp_captures;
-                            return Some(final_pos);
-                        }
-                    }
-                }
-                None
-            }
-
-            Token::Plus(inner) => {
-                // Must match at least once
-                if let Some(first_end) = self.match_single_token(chars, pos, inner, captures) {
-                    // Try matching more occurrences (greedy)
-                    let mut current_pos = first_end;
-                    let mut positions = vec![first_end];
-
-                    while let Some(next_end) =
-                        self.match_single_token(chars, current_pos, inner, captures)
-                    {
-                        positions.push(next_end);
-                        current_pos = next_end;
-                    }
-
-                    // Backtrack to find a valid continuation
-                    for &end_pos in positions.iter().rev() {
-                        if let Some(final_pos) = self.match_at(chars, end_pos, remaining, captures)
-                        {
-                            return Some(final_pos);
-                        }
-                    }
+                    options.files.push(arg);
                 }
-                None
+                continue;
             }
-
-            Token::Question(inner) => {
-                // Try matching the token first
-                let mut temp_captures = captures.clone();
-                if let Some(match_end) =
-                    self.match_single_token(chars, pos, inner, &mut temp_captures)
-                {
-                    if let Some(final_pos) =
-                        self.match_at(chars, match_end, remaining, &mut temp_captures)
-                    {
-                        *captures = temp_captures;
-                        return Some(final_pos);
+            let arg = into_utf8(arg)?;
+
+            if let Some(long) = arg.strip_prefix("--") {
+                // A value can follow '=' or come as the next argument
+                let (name, inline_value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (long, None),
+                };
+                let mut value = || match &inline_value {
+                    Some(value) => Ok(value.clone()),
+                    None => into_utf8(
+                        args.next()
+                            .ok_or_else(|| format!("option '--{}' needs a value", name))?,
+                    ),
+                };
+
+                match name {
+                    "" => options_done = true,
+                    // Patterns are always extended regular expressions
+                    "extended-regexp" => {}
+                    "ignore-case" => options.match_options.ignore_case = true,
+                    "ascii" => options.match_options.ascii = true,
+                    "only-matching" => options.only_matching = true,
+                    "invert-match" => options.invert = true,
+                    "count" => options.set_output(Output::Count),
+                    "files-with-matches" => options.set_output(Output::FilesWithMatches),
+                    "files-without-match" => options.set_output(Output::FilesWithoutMatch),
+                    "quiet" | "silent" => options.set_output(Output::Quiet),
+                    "max-count" => options.max_count = Some(parse_max_count(&value()?)?),
+                    "binary-files" => options.binary_files = BinaryFiles::parse(&value()?)?,
+                    "regex-step-limit" => {
+                        let limit = value()?;
+                        options.match_options.step_limit = limit
+                            .parse()
+                            .map_err(|_| format!("invalid step limit '{}'", limit))?;
                     }
+                    "capture" => options.capture = Some(value()?),
+                    "replace" => options.replace = Some(value()?),
+                    // The suffix is optional, so it can only follow '='
+                    "in-place" => options.in_place = Some(inline_value.clone().unwrap_or_default()),
+                    _ => return Err(format!("unknown option '{}'", arg)),
                 }
-
-                // If that fails, try skipping the optional token
-                self.match_at(chars, pos, remaining, captures)
+                continue;
             }
 
-            Token::Backreference(group_num) => {
-                if let Some(captured_text) = captures.get_group(*group_num) {
-                    let captured_chars: Vec<char> = captured_text.chars().collect();
-
-                    if pos + captured_chars.len() <= chars.len() {
-                        // Check if the characters match
-                        for (i, &ch) in captured_chars.iter().enumerate() {
-                            if chars[pos + i] != ch {
-                                return None;
-                            }
+            // Combined short options like -iE
+            for (i, flag) in arg.char_indices().skip(1) {
+                match flag {
+                    'E' => {}
+                    'i' => options.match_options.ignore_case = true,
+                    'o' => options.only_matching = true,
+                    'v' => options.invert = true,
+                    'c' => options.set_output(Output::Count),
+                    'l' => options.set_output(Output::FilesWithMatches),
+                    'L' => options.set_output(Output::FilesWithoutMatch),
+                    'q' => options.set_output(Output::Quiet),
+                    'm' | 'r' => {
+                        // The value is the rest of the argument or the next one
+                        let rest = &arg[i + 1..];
+                        let value = if rest.is_empty() {
+                            into_utf8(
+                                args.next()
+                                    .ok_or_else(|| format!("option '-{}' needs a value", flag))?,
+                            )?
+                        } else {
+                            rest.to_string()
+                        };
+                        if flag == 'm' {
+                            options.max_count = Some(parse_max_count(&value)?);
+                        } else {
+                            options.replace = Some(value);
                         }
-
-                        // Continue matching after the backreference
-                        self.match_at(chars, pos + captured_chars.len(), remaining, captures)
-                    } else {
-                        None
+                        break;
                     }
-                } else {
-                    None
+                    _ => return Err(format!("unknown option '-{}'", flag)),
                 }
             }
         }
-    }
-
-    /// Helper to match a single token occurrence
-    fn match_single_token(
-        &self,
-        chars: &[char],
-        pos: usize,
-        token: &Token,
-        captures: &mut Captures,
-    ) -> Option<usize> {
-        self.match_at(chars, pos, &[token.clone()], captures)
-    }
-}
 
-/// Parser for converting pattern strings into tokens
-struct Parser {
-    chars: Vec<char>,
-    pos: usize,
-    group_counter: usize,
-}
-
-impl Parser {
-    fn new(pattern: &str) -> Self {
-        Self {
-            chars: pattern.chars().collect(),
-            pos: 0,
-            group_counter: 1,
+        if options.capture.is_some() && options.replace.is_some() {
+            return Err("--capture can't be combined with --replace".to_string());
         }
-    }
-
-    fn parse(&mut self) -> Vec<Token> {
-        self.parse_sequence()
-    }
-
-    fn parse_sequence(&mut self) -> Vec<Token> {
-        let mut tokens = Vec::new();
-        let mut alternatives = Vec::new();
-
-        while self.pos < self.chars.len() {
-            match self.current_char() {
-                Some('^') if self.pos == 0 => {
-                    // Skip start anchor - handled in Matcher
-                    self.advance();
-                }
-                Some('$') if self.pos == self.chars.len() - 1 => {
-                    // Skip end anchor - handled in Matcher
-                    self.advance();
-                }
-                Some('|') => {
-                    // Handle alternation
-                    alternatives.push(tokens);
-                    tokens = Vec::new();
-                    self.advance();
-                }
-                Some(')') => {
-                    // End of group - don't consume the ')'
-                    break;
-                }
-                _ => {
-                    if let Some(token) = self.parse_atom() {
-                        tokens.push(token);
-                    }
-                }
-            }
+        if options.in_place.is_some() && options.replace.is_none() {
+            return Err("--in-place needs --replace".to_string());
         }
 
-        alternatives.push(tokens);
-
-        if alternatives.len() == 1 {
-            alternatives.into_iter().next().unwrap()
-        } else {
-            vec![Token::Alternative(alternatives)]
-        }
+        options.pattern = pattern.ok_or("no pattern given")?;
+        Ok(options)
     }
 
-    fn parse_atom(&mut self) -> Option<Token> {
-        let token = match self.current_char() {
-            Some('(') => Some(self.parse_group()),
-            Some('[') => Some(self.parse_char_class()),
-            Some('\\') => Some(self.parse_escape()),
-            Some('.') => {
-                self.advance();
-                Some(Token::Dot)
-            }
-            Some(ch) => {
-                self.advance();
-                Some(Token::Char(ch))
-            }
-            None => None,
+    /// Like GNU grep, -q beats -l and -L, which beat -c
+    fn set_output(&mut self, output: Output) {
+        let rank = |output| match output {
+            Output::Lines => 0,
+            Output::Count => 1,
+            Output::FilesWithMatches | Output::FilesWithoutMatch => 2,
+            Output::Quiet => 3,
         };
-
-        // Apply quantifiers if present
-        if let Some(token) = token {
-            Some(self.apply_quantifiers(token))
-        } else {
-            None
+        if rank(output) >= rank(self.output) {
+            self.output = output;
         }
     }
+}
 
-    fn apply_quantifiers(&mut self, mut token: Token) -> Token {
-        while let Some(ch) = self.current_char() {
-            match ch {
-                '+' => {
-                    token = Token::Plus(Box::new(token));
-                    self.advance();
-                }
-                '?' => {
-                    token = Token::Question(Box::new(token));
-                    self.advance();
-                }
-                _ => break,
-            }
-        }
-        token
-    }
+/// Convert an argument other than a file name, which has to be UTF-8
+fn into_utf8(arg: OsString) -> Result<String, String> {
+    arg.into_string()
+        .map_err(|arg| format!("invalid UTF-8 in argument '{}'", arg.to_string_lossy()))
+}
 
-    fn parse_group(&mut self) -> Token {
-        self.advance(); // Skip '('
-        let group_num = self.group_counter;
-        self.group_counter += 1;
+fn parse_max_count(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid max count '{}'", value))
+}
 
-        let group_tokens = self.parse_sequence();
+/// Settings shared by every file searched
+struct Searcher {
+    regex: Regex,
+    show_filename: bool,
+    binary_files: BinaryFiles,
+    // Select the lines that don't match instead
+    invert: bool,
+    output: Output,
+    // Stop reading a file after this many selected lines
+    max_count: Option<usize>,
+    // Print just this group of every match, one per line, instead of
+    // whole lines
+    only_matching: Option<usize>,
+    // Print matches expanded into this template, as by `Captures::expand`
+    replace: Option<String>,
+}
 
-        // Skip the closing ')'
-        if self.current_char() == Some(')') {
-            self.advance();
+impl Searcher {
+    /// Read `reader` line by line, selecting the lines that match (or with
+    /// -v, the ones that don't), and print what `output` asks for. Returns
+    /// how many lines were selected. Lines are raw bytes, so files that
+    /// aren't valid UTF-8 can still be searched.
+    fn search(
+        &self,
+        mut reader: impl BufRead,
+        name: &str,
+        out: &mut impl Write,
+    ) -> io::Result<usize> {
+        let binary_files = self.binary_files;
+        // Like GNU grep, a NUL byte in the first buffer marks a binary file
+        let mut binary = binary_files != BinaryFiles::Text && reader.fill_buf()?.contains(&0);
+        if binary && binary_files == BinaryFiles::WithoutMatch {
+            return self.summarize(name, 0, out);
         }
 
-        Token::Group(group_tokens, group_num)
-    }
+        // One selected line settles the answer in the modes that only
+        // report whether there was one
+        let limit = match self.output {
+            Output::Lines | Output::Count => self.max_count,
+            _ => Some(self.max_count.map_or(1, |max| max.min(1))),
+        };
+        let mut selected = 0;
+        let mut line = Vec::new();
 
-    fn parse_char_class(&mut self) -> Token {
-        self.advance(); // Skip '['
+        while limit != Some(selected) {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let (line, _) = split_line_ending(&line);
 
-        let negated = self.current_char() == Some('^');
-        if negated {
-            self.advance();
-        }
+            if self.regex.try_is_match(line).map_err(io::Error::other)? == self.invert {
+                continue;
+            }
+            selected += 1;
+            if self.output != Output::Lines {
+                continue;
+            }
 
-        let mut chars = Vec::new();
+            binary |= binary_files != BinaryFiles::Text && line.contains(&0);
+            if binary {
+                out.flush()?;
+                eprintln!("grep: {}: binary file matches", name);
+                break;
+            }
 
-        while self.pos < self.chars.len() {
-            match self.current_char() {
-                Some(']') => {
-                    self.advance();
-                    break;
-                }
-                Some(ch) => {
-                    // Handle ranges like a-z
-                    if self.pos + 2 < self.chars.len()
-                        && self.chars[self.pos + 1] == '-'
-                        && self.chars[self.pos + 2] != ']'
-                    {
-                        let start = ch;
-                        let end = self.chars[self.pos + 2];
-
-                        for c in start as u8..=end as u8 {
-                            chars.push(c as char);
+            match (self.only_matching, &self.replace) {
+                (Some(group), _) => {
+                    for captures in self.regex.try_captures_iter(line) {
+                        let captures = captures.map_err(io::Error::other)?;
+                        // Like GNU grep, skip empty matches
+                        let Some(found) = captures.get(group).filter(|m| !m.is_empty()) else {
+                            continue;
+                        };
+                        match &self.replace {
+                            Some(template) => {
+                                let mut expanded = Vec::new();
+                                captures.expand(template, &mut expanded);
+                                self.print(name, &expanded, out)?;
+                            }
+                            None => self.print(name, found.as_bytes(), out)?,
                         }
-
-                        self.pos += 3;
-                    } else {
-                        chars.push(ch);
-                        self.advance();
                     }
                 }
-                None => break,
+                (None, Some(template)) => {
+                    let replaced = self
+                        .regex
+                        .try_replace_all(line, template)
+                        .map_err(io::Error::other)?;
+                    self.print(name, &replaced, out)?;
+                }
+                (None, None) => self.print(name, line, out)?,
             }
         }
 
-        if negated {
-            Token::NegCharClass(chars)
-        } else {
-            Token::CharClass(chars)
-        }
+        self.summarize(name, selected, out)
     }
 
-    fn parse_escape(&mut self) -> Token {
-        self.advance(); // Skip '\'
-
-        match self.current_char() {
-            Some('d') => {
-                self.advance();
-                Token::Digit
-            }
-            Some('w') => {
-                self.advance();
-                Token::Word
-            }
-            Some('s') => {
-                self.advance();
-                Token::Whitespace
-            }
-            Some(ch) if ch.is_ascii_digit() => {
-                self.advance();
-                Token::Backreference(ch.to_digit(10).unwrap() as usize)
-            }
-            Some(ch) => {
-                self.advance();
-                Token::Char(ch)
-            }
-            None => Token::Char('\\'), // Trailing backslash
+    /// Print the per-file summary for the modes that have one, and pass
+    /// the number of selected lines on
+    fn summarize(&self, name: &str, selected: usize, out: &mut impl Write) -> io::Result<usize> {
+        match self.output {
+            Output::Count => self.print(name, selected.to_string().as_bytes(), out)?,
+            Output::FilesWithMatches if selected > 0 => writeln!(out, "{}", name)?,
+            Output::FilesWithoutMatch if selected == 0 => writeln!(out, "{}", name)?,
+            _ => {}
         }
-    }
-
-    fn current_char(&self) -> Option<char> {
-        self.chars.get(self.pos).copied()
-    }
-
-    fn advance(&mut self) {
-        self.pos += 1;
-    }
-}
-
-// Special matcher for the complex failing test case
-fn match_i_see_pattern(input: &str) -> bool {
-    if !input.starts_with("I see ") {
-        return false;
-    }
-
-    let rest = &input[6..]; // Skip "I see "
-    let chars: Vec<char> = rest.chars().collect();
-    let mut i = 0;
-    let mut matched_count = 0;
-
-    while i < chars.len() {
-        // Match \d
-        if i >= chars.len() || !chars[i].is_ascii_digit() {
-            break;
+        Ok(selected)
+    }
+
+    /// Replace every match in the file at `path` with `template`, leaving
+    /// line endings alone, and report whether anything matched. The file is
+    /// only written when something did, after copying the original to
+    /// `path` plus `backup_suffix` if the suffix isn't empty.
+    fn rewrite(&self, path: &OsStr, template: &str, backup_suffix: &str) -> io::Result<bool> {
+        let original = fs::read(path)?;
+        // Binary files are left alone, as with --binary-files=without-match
+        if self.binary_files != BinaryFiles::Text && original.contains(&0) {
+            return Ok(false);
         }
-        i += 1;
 
-        // Match space
-        if i >= chars.len() || chars[i] != ' ' {
-            break;
-        }
-        i += 1;
-
-        // Match (cat|dog|cow)
-        let mut matched_animal = false;
-        for animal in &["cat", "dog", "cow"] {
-            if i + animal.len() <= chars.len() {
-                let slice: String = chars[i..i + animal.len()].iter().collect();
-                if slice == *animal {
-                    i += animal.len();
-                    matched_animal = true;
-                    break;
-                }
-            }
+        let mut rewritten = Vec::with_capacity(original.len());
+        let mut matched = false;
+
+        for line in original.split_inclusive(|&byte| byte == b'\n') {
+            let (text, ending) = split_line_ending(line);
+            let replaced = self
+                .regex
+                .try_replace_all(text, template)
+                .map_err(io::Error::other)?;
+            matched |= matches!(replaced, Cow::Owned(_));
+            rewritten.extend_from_slice(&replaced);
+            rewritten.extend_from_slice(ending);
         }
 
-        if !matched_animal {
-            break;
+        if !matched {
+            return Ok(false);
         }
-
-        // Match s? (optional s)
-        if i < chars.len() && chars[i] == 's' {
-            i += 1;
+        if !backup_suffix.is_empty() {
+            let mut backup = path.to_os_string();
+            backup.push(backup_suffix);
+            fs::write(backup, &original)?;
         }
+        fs::write(path, rewritten)?;
+        Ok(true)
+    }
 
-        matched_count += 1;
-
-        // Match (, | and )? (optional separator)
-        if i + 2 <= chars.len() && chars[i] == ',' && chars[i + 1] == ' ' {
-            i += 2;
-        } else if i + 5 <= chars.len() {
-            let slice: String = chars[i..i + 5].iter().collect();
-            if slice == " and " {
-                i += 5;
-            } else if i < chars.len() {
-                // No separator matched, this should be the last item
-                break;
-            }
-        } else if i < chars.len() {
-            // No space for " and ", this should be the last item
-            break;
+    /// Print one line of output, prefixed with the file name when searching
+    /// several files
+    fn print(&self, name: &str, text: &[u8], out: &mut impl Write) -> io::Result<()> {
+        if self.show_filename {
+            out.write_all(name.as_bytes())?;
+            out.write_all(b":")?;
         }
+        out.write_all(text)?;
+        out.write_all(b"\n")
     }
-
-    // We should have consumed all characters and matched at least one pattern
-    i == chars.len() && matched_count > 0
 }
 
-// Special matcher for the failing backreference test case
-fn match_abc_def_pattern(input: &str) -> bool {
-    // Pattern: (([abc]+)-([def]+)) is \1, not ([^xyz]+), \2, or \3
-    // Input: "abc-def is abc-def, not efg, abc, or def"
-
-    // Parse the pattern step by step
-    let chars: Vec<char> = input.chars().collect();
-    let mut pos = 0;
-
-    // Match ([abc]+) - first part of group 1
-    let mut abc_part = String::new();
-    while pos < chars.len() && "abc".contains(chars[pos]) {
-        abc_part.push(chars[pos]);
-        pos += 1;
-    }
-    if abc_part.is_empty() {
-        return false;
-    }
-
-    // Match '-'
-    if pos >= chars.len() || chars[pos] != '-' {
-        return false;
-    }
-    pos += 1;
-
-    // Match ([def]+) - second part of group 1
-    let mut def_part = String::new();
-    while pos < chars.len() && "def".contains(chars[pos]) {
-        def_part.push(chars[pos]);
-        pos += 1;
-    }
-    if def_part.is_empty() {
-        return false;
-    }
+/// Split a line read with `read_until` into its text and its `\n` ending,
+/// if it has one. Like GNU grep, a `\r` before it is part of the text.
+fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let len = usize::from(line.ends_with(b"\n"));
+    line.split_at(line.len() - len)
+}
 
-    let group1 = format!("{}-{}", abc_part, def_part); // abc-def
-    let group2 = abc_part.clone(); // abc
-    let group3 = def_part.clone(); // def
+/// Find the group `--capture` asks for, by number or name
+fn capture_group(regex: &Regex, capture: &str) -> Result<usize, String> {
+    let group = match capture.parse::<usize>() {
+        Ok(group) => Some(group).filter(|&group| group < regex.captures_len()),
+        Err(_) => regex.capture_index(capture),
+    };
+    group.ok_or_else(|| format!("no capture group '{}' in pattern", capture))
+}
 
-    // Match " is "
-    if pos + 4 > chars.len() || &chars[pos..pos + 4].iter().collect::<String>() != " is " {
-        return false;
-    }
-    pos += 4;
+/// Print a parse error with a caret pointing at the offending character
+fn report_parse_error(pattern: &str, err: &ParseError) {
+    let column = pattern[..err.offset].chars().count();
+    eprintln!("grep: invalid pattern: {}", err.kind);
+    eprintln!("  {}", pattern);
+    eprintln!("  {}^", " ".repeat(column));
+}
 
-    // Match \1 (group1)
-    let group1_chars: Vec<char> = group1.chars().collect();
-    if pos + group1_chars.len() > chars.len() {
-        return false;
-    }
-    for (i, &ch) in group1_chars.iter().enumerate() {
-        if chars[pos + i] != ch {
-            return false;
+/// Main entry point
+fn main() {
+    let options = match Options::parse(env::args_os().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("grep: {}", message);
+            eprintln!("Usage: grep [-EcilLoqv] [-m NUM] [--ascii] [--binary-files=TYPE] [--capture=GROUP] [-r TEMPLATE [--in-place[=SUFFIX]]] [--regex-step-limit=N] PATTERN [FILE...]");
+            process::exit(2);
         }
-    }
-    pos += group1_chars.len();
-
-    // Match ", not "
-    if pos + 6 > chars.len() || &chars[pos..pos + 6].iter().collect::<String>() != ", not " {
-        return false;
-    }
-    pos += 6;
-
-    // Match ([^xyz]+) - group 4
-    let mut group4 = String::new();
-    while pos < chars.len() && !"xyz".contains(chars[pos]) && chars[pos] != ',' {
-        group4.push(chars[pos]);
-        pos += 1;
-    }
-    if group4.is_empty() {
-        return false;
-    }
+    };
 
-    // Match ", "
-    if pos + 2 > chars.len() || &chars[pos..pos + 2].iter().collect::<String>() != ", " {
-        return false;
-    }
-    pos += 2;
-
-    // Match \2 (group2)
-    let group2_chars: Vec<char> = group2.chars().collect();
-    if pos + group2_chars.len() > chars.len() {
-        return false;
-    }
-    for (i, &ch) in group2_chars.iter().enumerate() {
-        if chars[pos + i] != ch {
-            return false;
+    let regex = match Regex::with_options(&options.pattern, &options.match_options) {
+        Ok(regex) => regex,
+        Err(Error::Parse(err)) => {
+            report_parse_error(&options.pattern, &err);
+            process::exit(2);
         }
-    }
-    pos += group2_chars.len();
-
-    // Match ", or "
-    if pos + 5 > chars.len() || &chars[pos..pos + 5].iter().collect::<String>() != ", or " {
-        return false;
-    }
-    pos += 5;
-
-    // Match \3 (group3)
-    let group3_chars: Vec<char> = group3.chars().collect();
-    if pos + group3_chars.len() > chars.len() {
-        return false;
-    }
-    for (i, &ch) in group3_chars.iter().enumerate() {
-        if chars[pos + i] != ch {
-            return false;
+        Err(err) => {
+            eprintln!("grep: {}", err);
+            process::exit(2);
         }
-    }
-    pos += group3_chars.len();
-
-    // Should have consumed all input
-    pos == chars.len()
-}
-
-/// Main pattern matching function with special case handling
-fn match_pattern(input_line: &str, pattern: &str) -> bool {
-    // Special case for the complex "I see" pattern
-    if pattern == "^I see (\\d (cat|dog|cow)s?(, | and )?)+$" {
-        return match_i_see_pattern(input_line);
-    }
+    };
+
+    // --capture implies -o; plain -o prints the whole match
+    let only_matching = match &options.capture {
+        Some(capture) => match capture_group(&regex, capture) {
+            Ok(group) => Some(group),
+            Err(message) => {
+                eprintln!("grep: {}", message);
+                process::exit(2);
+            }
+        },
+        None => options.only_matching.then_some(0),
+    };
 
-    // Special case for the complex backreference pattern
-    if pattern == "(([abc]+)-([def]+)) is \\1, not ([^xyz]+), \\2, or \\3" {
-        return match_abc_def_pattern(input_line);
-    }
+    // With no files, read stdin
+    let files = if options.files.is_empty() {
+        vec![OsString::from("-")]
+    } else {
+        options.files
+    };
+    let searcher = Searcher {
+        regex,
+        show_filename: files.len() > 1,
+        binary_files: options.binary_files,
+        invert: options.invert,
+        output: options.output,
+        max_count: options.max_count,
+        only_matching,
+        replace: options.replace,
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let mut any_matched = false;
+    let mut had_error = false;
+
+    for file in &files {
+        let name = if file == "-" {
+            Cow::Borrowed("(standard input)")
+        } else {
+            file.to_string_lossy()
+        };
+        let result = if let (Some(suffix), Some(template)) = (&options.in_place, &searcher.replace)
+        {
+            if file == "-" {
+                Err(io::Error::other("can't edit standard input in place"))
+            } else {
+                searcher.rewrite(file, template, suffix).map(usize::from)
+            }
+        } else if file == "-" {
+            searcher.search(io::stdin().lock(), &name, &mut out)
+        } else {
+            File::open(file).and_then(|f| searcher.search(BufReader::new(f), &name, &mut out))
+        };
 
-    // Use the general regex engine for all other patterns
-    let matcher = Matcher::new(pattern);
-    matcher.is_match(input_line)
-}
+        match result {
+            // Like GNU grep, even -L succeeds when some line was selected
+            Ok(selected) => any_matched |= selected > 0,
+            Err(err) => {
+                eprintln!("grep: {}: {}", name, err);
+                had_error = true;
+            }
+        }
 
-/// Main entry point
-fn main() {
-    // Validate arguments
-    if env::args().nth(1).unwrap() != "-E" {
-        println!("Expected first argument to be '-E'");
-        process::exit(1);
+        // -q has its answer as soon as anything matches
+        if any_matched && searcher.output == Output::Quiet {
+            break;
+        }
     }
 
-    let pattern = env::args().nth(2).unwrap();
-    let mut input_line = String::new();
-
-    io::stdin().read_line(&mut input_line).unwrap();
-
-    // Remove trailing newline
-    if input_line.ends_with('\n') {
-        input_line.pop();
+    if let Err(err) = out.flush() {
+        eprintln!("grep: {}", err);
+        had_error = true;
     }
 
-    // Test pattern using combined approach
-    if match_pattern(&input_line, &pattern) {
+    // Like GNU grep, an error wins over a match, except with -q
+    if had_error && !(any_matched && searcher.output == Output::Quiet) {
+        process::exit(2);
+    } else if any_matched {
         process::exit(0);
     } else {
         process::exit(1);
@@ -0,0 +1,196 @@
+//! Matching engine: picks between the DFA, the Pike VM and the
+//! backtracker for each search
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::backtrack::Backtracker;
+use crate::dfa::Dfa;
+use crate::parser::{ParseError, Parser};
+use crate::pikevm::{Program, Slots};
+use crate::prefilter::Prefilter;
+use crate::token::Token;
+use crate::Error;
+
+/// Input decoded once for the char-based matchers
+#[derive(Debug)]
+pub struct Decoded {
+    pub chars: Vec<char>,
+    // Byte offset of every char, followed by the length of the input
+    pub offsets: Vec<usize>,
+}
+
+impl Decoded {
+    /// Decode UTF-8, reading each maximal invalid sequence as U+FFFD
+    pub fn new(input: &[u8]) -> Self {
+        let mut chars = Vec::with_capacity(input.len());
+        let mut offsets = Vec::with_capacity(input.len() + 1);
+        let mut offset = 0;
+
+        for chunk in input.utf8_chunks() {
+            for (i, ch) in chunk.valid().char_indices() {
+                chars.push(ch);
+                offsets.push(offset + i);
+            }
+            offset += chunk.valid().len();
+
+            if !chunk.invalid().is_empty() {
+                chars.push(char::REPLACEMENT_CHARACTER);
+                offsets.push(offset);
+                offset += chunk.invalid().len();
+            }
+        }
+        offsets.push(offset);
+
+        Self { chars, offsets }
+    }
+}
+
+/// Backtracking choice points allowed per start position unless
+/// `MatchOptions::step_limit` says otherwise
+const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+/// Settings that apply to a whole pattern
+#[derive(Debug, Clone)]
+pub struct MatchOptions {
+    pub ignore_case: bool, // Same as starting the pattern with (?i)
+    pub ascii: bool,       // Keep \d, \w, \s and \b to ASCII
+    pub step_limit: usize, // Backtracking steps per start, 0 for no limit
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            ignore_case: false,
+            ascii: false,
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+}
+
+/// Main pattern matcher
+#[derive(Debug)]
+pub struct Matcher {
+    // Number of capture groups, not counting the whole match
+    groups: usize,
+    group_names: Arc<HashMap<String, usize>>,
+    ascii: bool,
+    // The pattern starts with ^, so it can only match at position 0
+    anchored: bool,
+    // Linear-time program, unless the pattern needs the backtracker
+    program: Option<Program>,
+    // State cache for yes/no matching with `program`
+    dfa: Option<Mutex<Dfa>>,
+    // Used instead of `program` when there isn't one
+    backtracker: Option<Backtracker>,
+    // Literal that every match contains
+    prefilter: Option<Prefilter>,
+    step_limit: usize,
+}
+
+impl Matcher {
+    pub fn new(pattern: &str, options: &MatchOptions) -> Result<Self, ParseError> {
+        let mut parser = Parser::new(pattern, options);
+        let tokens = parser.parse()?;
+        let groups = parser.group_counter - 1;
+        let program = Program::compile(&tokens, groups);
+        let anchored = matches!(tokens.first(), Some(Token::StartAnchor));
+        let dfa = program
+            .as_ref()
+            .map(|_| Mutex::new(Dfa::new(anchored, options.ascii)));
+        let backtracker = match program {
+            Some(_) => None,
+            None => Some(Backtracker::new(&tokens, groups, &parser.group_names)),
+        };
+        let prefilter = Prefilter::new(&tokens);
+
+        Ok(Self {
+            groups,
+            group_names: Arc::new(parser.group_names),
+            ascii: options.ascii,
+            anchored,
+            program,
+            dfa,
+            backtracker,
+            prefilter,
+            step_limit: options.step_limit,
+        })
+    }
+
+    /// Number of capture groups, not counting the whole match
+    pub fn groups(&self) -> usize {
+        self.groups
+    }
+
+    pub fn group_names(&self) -> &Arc<HashMap<String, usize>> {
+        &self.group_names
+    }
+
+    /// Check if the pattern matches the input
+    pub fn is_match(&self, input: &[u8]) -> Result<bool, Error> {
+        if let Some(prefilter) = &self.prefilter {
+            if !prefilter.could_match(input) {
+                return Ok(false);
+            }
+        }
+
+        if let (Some(program), Some(dfa)) = (&self.program, &self.dfa) {
+            // Another thread holding the cache just means using the Pike VM
+            if let Ok(mut dfa) = dfa.try_lock() {
+                if let Some(matched) = dfa.is_match(program, input) {
+                    return Ok(matched);
+                }
+            }
+        }
+
+        let decoded = Decoded::new(input);
+        Ok(self.search(input, &decoded, 0)?.is_some())
+    }
+
+    /// Find the leftmost-first match that starts at or after char `start`,
+    /// returning the capture slots as char positions: slots 2n and 2n+1
+    /// hold the span of group n, with group 0 spanning the whole match
+    pub fn search(
+        &self,
+        input: &[u8],
+        decoded: &Decoded,
+        start: usize,
+    ) -> Result<Option<Slots>, Error> {
+        let Decoded { chars, offsets } = decoded;
+        let from = offsets[start];
+        let candidates = match &self.prefilter {
+            Some(prefilter) if !self.anchored => prefilter.candidates(input, from),
+            _ => None,
+        };
+        // Finding the first candidate does the same check
+        if let (Some(prefilter), None) = (&self.prefilter, &candidates) {
+            if !prefilter.could_match(&input[from..]) {
+                return Ok(None);
+            }
+        }
+
+        // Starts are produced as they're tried, so a search that matches
+        // early doesn't pay for the rest of the line
+        let mut starts: Box<dyn Iterator<Item = usize>> = match candidates {
+            _ if self.anchored => Box::new((start == 0).then_some(0).into_iter()),
+            Some(candidates) => {
+                Box::new(candidates.filter_map(|candidate| offsets.binary_search(&candidate).ok()))
+            }
+            None => Box::new(start..=chars.len()),
+        };
+
+        if let Some(program) = &self.program {
+            // The VM tries every later start itself
+            return Ok(match starts.next() {
+                Some(first) => program.search(chars, first, self.anchored, self.ascii),
+                None => None,
+            });
+        }
+
+        let backtracker = self
+            .backtracker
+            .as_ref()
+            .expect("patterns the Pike VM can't run have a backtracker");
+        backtracker.search(chars, starts, self.ascii, self.step_limit)
+    }
+}
@@ -0,0 +1,877 @@
+//! Pattern parser, turning a pattern string into a `Token` tree
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::charset::{fold_case, is_word_char, posix_class, CharSet, Shorthand};
+use crate::token::{max_width, Token};
+use crate::unicode;
+use crate::MatchOptions;
+
+/// One entry of a bracket expression: a single char can start a range,
+/// anything else is just added to the class
+enum ClassItem {
+    Literal(char),
+    Set(CharSet),
+}
+
+/// What went wrong while parsing a pattern
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseErrorKind {
+    #[error("unclosed group")]
+    UnclosedGroup,
+    #[error("unknown group type after '(?'")]
+    UnknownGroupKind,
+    #[error("unknown flag '{0}'")]
+    UnknownFlag(char),
+    #[error("invalid group name")]
+    InvalidGroupName,
+    #[error("duplicate group name '{0}'")]
+    DuplicateGroupName(String),
+    #[error("unknown group name '{0}'")]
+    UnknownGroupName(String),
+    #[error("backreference to nonexistent group {0}")]
+    UnknownGroup(usize),
+    #[error("unmatched ')'")]
+    UnmatchedParen,
+    #[error("unterminated character class")]
+    UnclosedClass,
+    #[error("quantifier '{0}' has nothing to repeat")]
+    DanglingQuantifier(char),
+    #[error("invalid range '{0}-{1}'")]
+    ReversedRange(char, char),
+    #[error("repetition bounds '{{{0},{1}}}' are reversed")]
+    ReversedBounds(usize, usize),
+    #[error("repetition count '{0}' is too large")]
+    RepetitionTooLarge(String),
+    #[error("range endpoint must be a single character")]
+    InvalidRange,
+    #[error("unknown POSIX class '{0}'")]
+    UnknownPosixClass(String),
+    #[error("equivalence class '{0}' must be a single character")]
+    InvalidEquivalenceClass(String),
+    #[error("expected a property name like \\pL or \\p{{Greek}}")]
+    UnclosedProperty,
+    #[error("unknown Unicode property '{0}'")]
+    UnknownProperty(String),
+    #[error("trailing backslash")]
+    TrailingBackslash,
+    #[error("unknown escape '\\{0}'")]
+    UnknownEscape(char),
+    #[error("invalid hex or Unicode escape")]
+    InvalidCodePoint,
+}
+
+/// A malformed pattern, with the byte offset of the offending character
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind} at offset {offset}")]
+pub struct ParseError {
+    pub offset: usize,
+    pub kind: ParseErrorKind,
+}
+
+/// Parser for converting pattern strings into tokens
+pub struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    pub group_counter: usize,
+    pub group_names: HashMap<String, usize>,
+    // \1 and \k<name> references, checked once every group has been seen
+    numbered_references: Vec<(usize, usize)>,
+    named_references: Vec<(usize, String)>,
+    // Whether (?i) is in effect at the current position
+    ignore_case: bool,
+    ascii: bool,
+    // Whether '[' inside brackets starts a nested class
+    nested_classes: bool,
+}
+
+impl Parser {
+    pub fn new(pattern: &str, options: &MatchOptions) -> Self {
+        Self {
+            chars: pattern.chars().collect(),
+            pos: 0,
+            group_counter: 1,
+            group_names: HashMap::new(),
+            numbered_references: Vec::new(),
+            named_references: Vec::new(),
+            ignore_case: options.ignore_case,
+            ascii: options.ascii,
+            nested_classes: true,
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Token>, ParseError> {
+        let tokens = self.parse_sequence()?;
+
+        // parse_sequence only stops early at a ')' with no matching '('
+        if self.pos < self.chars.len() {
+            return Err(self.error_at(self.pos, ParseErrorKind::UnmatchedParen));
+        }
+
+        for &(pos, group) in &self.numbered_references {
+            if group >= self.group_counter {
+                return Err(self.error_at(pos, ParseErrorKind::UnknownGroup(group)));
+            }
+        }
+        for (pos, name) in &self.named_references {
+            if !self.group_names.contains_key(name) {
+                return Err(self.error_at(*pos, ParseErrorKind::UnknownGroupName(name.clone())));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut alternatives = Vec::new();
+
+        while self.pos < self.chars.len() {
+            match self.current_char() {
+                Some('|') => {
+                    // Handle alternation
+                    alternatives.push(tokens);
+                    tokens = Vec::new();
+                    self.advance();
+                }
+                Some(')') => {
+                    // End of group - don't consume the ')'
+                    break;
+                }
+                Some('\\') if self.peek_char(1) == Some('Q') => {
+                    // A quantifier after \Q...\E only repeats its last char
+                    let quoted = self.parse_quoted();
+                    if let Some((&last, rest)) = quoted.split_last() {
+                        tokens.extend(rest.iter().map(|&ch| self.apply_case(Token::Char(ch))));
+                        let last = self.apply_case(Token::Char(last));
+                        tokens.push(self.apply_quantifiers(last)?);
+                    }
+                }
+                _ => {
+                    if let Some(token) = self.parse_atom()? {
+                        tokens.push(token);
+                    }
+                }
+            }
+        }
+
+        alternatives.push(tokens);
+
+        if alternatives.len() == 1 {
+            Ok(alternatives.into_iter().next().unwrap())
+        } else {
+            Ok(vec![Token::Alternative(alternatives)])
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Option<Token>, ParseError> {
+        let token = match self.current_char() {
+            Some('(') => self.parse_group()?,
+            Some('[') => Some(self.parse_char_class()?),
+            Some('\\') => Some(self.parse_escape()?),
+            Some(ch @ ('*' | '+' | '?')) => {
+                return Err(self.error_at(self.pos, ParseErrorKind::DanglingQuantifier(ch)));
+            }
+            Some('{') => {
+                let start = self.pos;
+                if self.parse_bounds()?.is_some() {
+                    return Err(self.error_at(start, ParseErrorKind::DanglingQuantifier('{')));
+                }
+                self.advance();
+                Some(Token::Char('{'))
+            }
+            Some('.') => {
+                self.advance();
+                Some(Token::Dot)
+            }
+            Some('^') => {
+                self.advance();
+                Some(Token::StartAnchor)
+            }
+            Some('$') => {
+                self.advance();
+                Some(Token::EndAnchor)
+            }
+            Some(ch) => {
+                self.advance();
+                Some(Token::Char(ch))
+            }
+            None => None,
+        };
+
+        let token = token.map(|token| self.apply_case(token));
+
+        // Apply quantifiers if present
+        token.map(|token| self.apply_quantifiers(token)).transpose()
+    }
+
+    /// Mark a token as case-insensitive if (?i) is in effect, folding any
+    /// literal chars it holds
+    fn apply_case(&self, token: Token) -> Token {
+        if !self.ignore_case {
+            return token;
+        }
+
+        let token = match token {
+            Token::Char(ch) => Token::Char(fold_case(ch)),
+            Token::CharClass(set) => Token::CharClass(set.with_folded()),
+            Token::NegCharClass(set) => Token::NegCharClass(set.with_folded()),
+            Token::Backreference(_) | Token::NamedBackreference(_) => token,
+            _ => return token,
+        };
+        Token::IgnoreCase(Box::new(token))
+    }
+
+    fn apply_quantifiers(&mut self, mut token: Token) -> Result<Token, ParseError> {
+        loop {
+            let (min, max) = match self.current_char() {
+                Some('*') => {
+                    self.advance();
+                    (0, None)
+                }
+                Some('+') => {
+                    self.advance();
+                    (1, None)
+                }
+                Some('?') => {
+                    self.advance();
+                    (0, Some(1))
+                }
+                Some('{') => match self.parse_bounds()? {
+                    Some(bounds) => bounds,
+                    // Not a valid {n,m}, so the '{' is a literal
+                    None => break,
+                },
+                _ => break,
+            };
+
+            // A trailing '?' makes the quantifier lazy
+            let greedy = self.current_char() != Some('?');
+            if !greedy {
+                self.advance();
+            }
+
+            token = Token::Repeat {
+                token: Box::new(token),
+                min,
+                max,
+                greedy,
+            };
+        }
+        Ok(token)
+    }
+
+    /// Parse `{n}`, `{n,}` or `{n,m}`, leaving the position untouched if the
+    /// braces don't hold a repetition count. A count like `{2,1}`, or one
+    /// too large to represent, is an error rather than a literal.
+    fn parse_bounds(&mut self) -> Result<Option<(usize, Option<usize>)>, ParseError> {
+        let start = self.pos;
+        self.advance(); // Skip '{'
+
+        let min = self.parse_digits();
+        let max = if !min.is_empty() && self.current_char() == Some(',') {
+            self.advance();
+            Some(self.parse_digits())
+        } else {
+            None
+        };
+        if min.is_empty() || self.current_char() != Some('}') {
+            self.pos = start;
+            return Ok(None);
+        }
+        self.advance(); // Skip '}'
+
+        let count = |digits: String| {
+            digits
+                .parse::<usize>()
+                .map_err(|_| self.error_at(start, ParseErrorKind::RepetitionTooLarge(digits)))
+        };
+        let min = count(min)?;
+        let max = match max {
+            None => Some(min),
+            Some(digits) if digits.is_empty() => None,
+            Some(digits) => Some(count(digits)?),
+        };
+        if let Some(max) = max.filter(|&max| max < min) {
+            return Err(self.error_at(start, ParseErrorKind::ReversedBounds(min, max)));
+        }
+        Ok(Some((min, max)))
+    }
+
+    fn parse_digits(&mut self) -> String {
+        let start = self.pos;
+        while self.current_char().is_some_and(|ch| ch.is_ascii_digit()) {
+            self.advance();
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// Parse a group, or return `None` for a bare `(?i)` that only
+    /// changes flags
+    fn parse_group(&mut self) -> Result<Option<Token>, ParseError> {
+        let start = self.pos;
+        self.advance(); // Skip '('
+
+        if self.current_char() == Some('?') {
+            return self.parse_extension_group(start);
+        }
+
+        let group_num = self.group_counter;
+        self.group_counter += 1;
+
+        let group_tokens = self.parse_group_body(start)?;
+        Ok(Some(Token::Group(group_tokens, group_num)))
+    }
+
+    /// Parse a `(?...)` group, with the position just past the '('
+    fn parse_extension_group(&mut self, start: usize) -> Result<Option<Token>, ParseError> {
+        self.advance(); // Skip '?'
+
+        let token = match (self.current_char(), self.peek_char(1)) {
+            (Some(':'), _) => {
+                self.advance();
+                Token::NonCapturing(self.parse_group_body(start)?)
+            }
+            (Some('>'), _) => {
+                self.advance();
+                Token::Atomic(self.parse_group_body(start)?)
+            }
+            (Some(ch @ ('=' | '!')), _) => {
+                self.advance();
+                let tokens = self.parse_group_body(start)?;
+                Token::LookAhead {
+                    tokens,
+                    negated: ch == '!',
+                }
+            }
+            (Some('<'), Some(ch @ ('=' | '!'))) => {
+                self.pos += 2;
+                let tokens = self.parse_group_body(start)?;
+                let max_width = max_width(&tokens);
+                Token::LookBehind {
+                    tokens,
+                    negated: ch == '!',
+                    max_width,
+                }
+            }
+            (Some('P'), Some('<')) => {
+                self.advance();
+                self.parse_named_group(start)?
+            }
+            (Some('<'), _) => self.parse_named_group(start)?,
+            (Some(ch), _) if ch == '-' || ch.is_ascii_alphabetic() => {
+                return self.parse_flag_group(start);
+            }
+            _ => return Err(self.error_at(start, ParseErrorKind::UnknownGroupKind)),
+        };
+        Ok(Some(token))
+    }
+
+    /// Parse `(?i)`, `(?-i)` or `(?i:...)`. The bare form changes flags for
+    /// the rest of the enclosing group; the scoped form only for its body
+    fn parse_flag_group(&mut self, start: usize) -> Result<Option<Token>, ParseError> {
+        let outer_ignore_case = self.ignore_case;
+        let mut enable = true;
+
+        loop {
+            match self.current_char() {
+                Some('i') => self.ignore_case = enable,
+                Some('-') if enable => enable = false,
+                Some(')') => {
+                    self.advance();
+                    return Ok(None);
+                }
+                Some(':') => {
+                    self.advance();
+                    let tokens = self.parse_group_body(start)?;
+                    self.ignore_case = outer_ignore_case;
+                    return Ok(Some(Token::NonCapturing(tokens)));
+                }
+                Some(ch) if ch.is_ascii_alphabetic() => {
+                    return Err(self.error_at(self.pos, ParseErrorKind::UnknownFlag(ch)));
+                }
+                Some(_) => return Err(self.error_at(start, ParseErrorKind::UnknownGroupKind)),
+                None => return Err(self.error_at(start, ParseErrorKind::UnclosedGroup)),
+            }
+            self.advance();
+        }
+    }
+
+    /// Parse `<name>...)` of a named capturing group
+    fn parse_named_group(&mut self, start: usize) -> Result<Token, ParseError> {
+        let name = self.parse_group_name()?;
+        if self.group_names.contains_key(&name) {
+            return Err(self.error_at(start, ParseErrorKind::DuplicateGroupName(name)));
+        }
+
+        let group_num = self.group_counter;
+        self.group_counter += 1;
+        self.group_names.insert(name, group_num);
+
+        let group_tokens = self.parse_group_body(start)?;
+        Ok(Token::Group(group_tokens, group_num))
+    }
+
+    /// Parse a `<name>` used by named groups and `\k<name>`
+    fn parse_group_name(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        if self.current_char() != Some('<') {
+            return Err(self.error_at(start, ParseErrorKind::InvalidGroupName));
+        }
+        self.advance(); // Skip '<'
+
+        let name_start = self.pos;
+        while self.current_char().is_some_and(is_word_char) {
+            self.advance();
+        }
+        let name: String = self.chars[name_start..self.pos].iter().collect();
+
+        let starts_with_digit = name.starts_with(|ch: char| ch.is_ascii_digit());
+        if name.is_empty() || starts_with_digit || self.current_char() != Some('>') {
+            return Err(self.error_at(start, ParseErrorKind::InvalidGroupName));
+        }
+        self.advance(); // Skip '>'
+
+        Ok(name)
+    }
+
+    /// Parse the contents of a group up to and including its closing ')'
+    fn parse_group_body(&mut self, start: usize) -> Result<Vec<Token>, ParseError> {
+        // A bare (?i) inside the group stops applying at its ')'
+        let ignore_case = self.ignore_case;
+        let tokens = self.parse_sequence()?;
+        self.ignore_case = ignore_case;
+
+        // Skip the closing ')'
+        if self.current_char() != Some(')') {
+            return Err(self.error_at(start, ParseErrorKind::UnclosedGroup));
+        }
+        self.advance();
+
+        Ok(tokens)
+    }
+
+    fn parse_char_class(&mut self) -> Result<Token, ParseError> {
+        let start = self.pos;
+        let (negated, set) = match self.parse_bracket_set() {
+            // A nested class that swallowed the only ']' leaves the class
+            // unclosed, so read every '[' as a plain char instead, as POSIX
+            // does: [[] matches '['
+            Err(ParseError {
+                kind: ParseErrorKind::UnclosedClass,
+                ..
+            }) if self.nested_classes => {
+                self.pos = start;
+                self.nested_classes = false;
+                let result = self.parse_bracket_set();
+                self.nested_classes = true;
+                result?
+            }
+            result => result?,
+        };
+        if negated {
+            Ok(Token::NegCharClass(set))
+        } else {
+            Ok(Token::CharClass(set))
+        }
+    }
+
+    /// Parse a bracket expression from its '[' through its ']'. Unions bind
+    /// tightest, then `&&` (intersection), `--` (difference) and `~~`
+    /// (symmetric difference) apply left to right, and a leading '^' negates
+    /// the result, which is returned separately. As in POSIX, a ']' right
+    /// after the '[' or '[^' is a literal, so []a] matches ']' or 'a'.
+    fn parse_bracket_set(&mut self) -> Result<(bool, CharSet), ParseError> {
+        let start = self.pos;
+        self.advance(); // Skip '['
+
+        let negated = self.current_char() == Some('^');
+        if negated {
+            self.advance();
+        }
+
+        let mut set = self.parse_class_union(start, true)?;
+        loop {
+            let operator = match (self.current_char(), self.peek_char(1)) {
+                (Some(']'), _) => break,
+                (Some(operator), _) => operator,
+                (None, _) => return Err(self.error_at(start, ParseErrorKind::UnclosedClass)),
+            };
+            self.pos += 2;
+
+            let operand = self.parse_class_union(start, false)?;
+            set = match operator {
+                '&' => set.intersection(&operand),
+                '-' => set.difference(&operand),
+                _ => set.symmetric_difference(&operand),
+            };
+        }
+        self.advance(); // Skip ']'
+
+        Ok((negated, set))
+    }
+
+    /// Parse chars, ranges, backslash classes and nested brackets up to the
+    /// closing ']' or the next set operator. With `leading`, a ']' before
+    /// anything else is a literal.
+    fn parse_class_union(&mut self, start: usize, leading: bool) -> Result<CharSet, ParseError> {
+        let mut ranges = Vec::new();
+        let first = self.pos;
+
+        loop {
+            let item_start = self.pos;
+            let item = match (self.current_char(), self.peek_char(1)) {
+                (Some(']'), _) if !leading || self.pos != first => break,
+                (Some(ch @ ('&' | '-' | '~')), Some(next)) if next == ch => break,
+                (Some('\\'), Some('Q')) => {
+                    ranges.extend(self.parse_quoted().into_iter().map(|ch| (ch, ch)));
+                    continue;
+                }
+                // A nested class, unless it's [:name:] or [=c=]
+                (Some('['), Some(next)) if self.nested_classes && next != ':' && next != '=' => {
+                    let (negated, set) = self.parse_bracket_set()?;
+                    ranges.extend(if negated { set.negated() } else { set }.into_ranges());
+                    continue;
+                }
+                (Some(_), _) => self.parse_class_item()?,
+                (None, _) => return Err(self.error_at(start, ParseErrorKind::UnclosedClass)),
+            };
+
+            // Handle ranges like a-z; a '-' right before ']' is a literal and
+            // one before another '-' starts the difference operator
+            let is_range = self.current_char() == Some('-')
+                && self.peek_char(1).is_some_and(|ch| ch != ']' && ch != '-');
+
+            match item {
+                ClassItem::Literal(range_start) if is_range => {
+                    self.advance(); // Skip '-'
+                    let range_end = match self.parse_class_item()? {
+                        ClassItem::Literal(ch) => ch,
+                        _ => return Err(self.error_at(item_start, ParseErrorKind::InvalidRange)),
+                    };
+                    if range_start > range_end {
+                        return Err(self.error_at(
+                            item_start,
+                            ParseErrorKind::ReversedRange(range_start, range_end),
+                        ));
+                    }
+                    ranges.push((range_start, range_end));
+                }
+                ClassItem::Literal(ch) => ranges.push((ch, ch)),
+                ClassItem::Set(set) => ranges.extend(set.into_ranges()),
+            }
+        }
+
+        Ok(CharSet::from_ranges(ranges))
+    }
+
+    /// Parse a single char or backslash class inside brackets
+    fn parse_class_item(&mut self) -> Result<ClassItem, ParseError> {
+        let ch = match self.current_char() {
+            Some(ch) => ch,
+            None => return Err(self.error_at(self.pos, ParseErrorKind::UnclosedClass)),
+        };
+        self.advance();
+        if ch == '[' {
+            return Ok(self.parse_posix_item()?.unwrap_or(ClassItem::Literal('[')));
+        }
+        if ch != '\\' {
+            return Ok(ClassItem::Literal(ch));
+        }
+
+        let escaped = match self.current_char() {
+            Some(escaped) => escaped,
+            None => return Err(self.error_at(self.pos - 1, ParseErrorKind::TrailingBackslash)),
+        };
+        if !matches!(escaped, 'b' | 'd' | 'w' | 's' | 'D' | 'W' | 'S' | 'p' | 'P') {
+            return Ok(ClassItem::Literal(self.parse_char_escape()?));
+        }
+        self.advance();
+
+        let item = match escaped {
+            // Inside brackets \b is a backspace rather than a word boundary
+            'b' => ClassItem::Literal('\x08'),
+            'd' => ClassItem::Set(Shorthand::Digit.char_set(self.ascii)),
+            'w' => ClassItem::Set(Shorthand::Word.char_set(self.ascii)),
+            's' => ClassItem::Set(Shorthand::Whitespace.char_set(self.ascii)),
+            'D' => ClassItem::Set(Shorthand::Digit.char_set(self.ascii).negated()),
+            'W' => ClassItem::Set(Shorthand::Word.char_set(self.ascii).negated()),
+            'S' => ClassItem::Set(Shorthand::Whitespace.char_set(self.ascii).negated()),
+            _ => {
+                let set = self.parse_property()?;
+                ClassItem::Set(if escaped == 'P' { set.negated() } else { set })
+            }
+        };
+        Ok(item)
+    }
+
+    /// Parse the rest of a `[:name:]`, `[:^name:]` or `[=c=]` inside brackets,
+    /// with the position just past its '['. Returns `None`, consuming
+    /// nothing, if this '[' doesn't start one
+    fn parse_posix_item(&mut self) -> Result<Option<ClassItem>, ParseError> {
+        let start = self.pos - 1;
+        let delimiter = match self.current_char() {
+            Some(ch @ (':' | '=')) => ch,
+            _ => return Ok(None),
+        };
+
+        let name_start = self.pos + 1;
+        let name_end = match (name_start..self.chars.len().saturating_sub(1))
+            .find(|&i| self.chars[i] == delimiter && self.chars[i + 1] == ']')
+        {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let name: String = self.chars[name_start..name_end].iter().collect();
+        self.pos = name_end + 2;
+
+        if delimiter == '=' {
+            // Equivalence classes only hold the char itself
+            let mut chars = name.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(ch), None) => Ok(Some(ClassItem::Set(CharSet::from_ranges([(ch, ch)])))),
+                _ => Err(self.error_at(start, ParseErrorKind::InvalidEquivalenceClass(name))),
+            };
+        }
+
+        let (negated, class_name) = match name.strip_prefix('^') {
+            Some(class_name) => (true, class_name),
+            None => (false, name.as_str()),
+        };
+        match posix_class(class_name) {
+            Some(set) if negated => Ok(Some(ClassItem::Set(set.negated()))),
+            Some(set) => Ok(Some(ClassItem::Set(set))),
+            None => Err(self.error_at(start, ParseErrorKind::UnknownPosixClass(name))),
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<Token, ParseError> {
+        self.advance(); // Skip '\'
+
+        let token = match self.current_char() {
+            Some('d') => {
+                self.advance();
+                Token::Digit
+            }
+            Some('w') => {
+                self.advance();
+                Token::Word
+            }
+            Some('s') => {
+                self.advance();
+                Token::Whitespace
+            }
+            Some('D') => {
+                self.advance();
+                Token::NegCharClass(Shorthand::Digit.char_set(self.ascii))
+            }
+            Some('W') => {
+                self.advance();
+                Token::NegCharClass(Shorthand::Word.char_set(self.ascii))
+            }
+            Some('S') => {
+                self.advance();
+                Token::NegCharClass(Shorthand::Whitespace.char_set(self.ascii))
+            }
+            Some(escaped @ ('p' | 'P')) => {
+                self.advance();
+                let set = self.parse_property()?;
+                if escaped == 'P' {
+                    Token::NegCharClass(set)
+                } else {
+                    Token::CharClass(set)
+                }
+            }
+            Some('A') => {
+                self.advance();
+                Token::StartAnchor
+            }
+            Some('b') => {
+                self.advance();
+                Token::WordBoundary
+            }
+            Some('B') => {
+                self.advance();
+                Token::NotWordBoundary
+            }
+            Some('<') => {
+                self.advance();
+                Token::StartOfWord
+            }
+            Some('>') => {
+                self.advance();
+                Token::EndOfWord
+            }
+            Some('Z') => {
+                self.advance();
+                Token::EndAnchor
+            }
+            Some('z') => {
+                self.advance();
+                Token::EndOfText
+            }
+            Some(ch @ '1'..='9') => {
+                self.advance();
+                let group = ch.to_digit(10).unwrap() as usize;
+                self.numbered_references.push((self.pos - 2, group));
+                Token::Backreference(group)
+            }
+            Some('k') => {
+                let start = self.pos - 1;
+                self.advance();
+                let name = self.parse_group_name()?;
+                self.named_references.push((start, name.clone()));
+                Token::NamedBackreference(name)
+            }
+            Some(_) => Token::Char(self.parse_char_escape()?),
+            None => {
+                return Err(self.error_at(self.pos - 1, ParseErrorKind::TrailingBackslash));
+            }
+        };
+        Ok(token)
+    }
+
+    /// Parse an escape that stands for a single char, with the position just
+    /// past the backslash: a control escape like `\n`, `\xHH`, `\x{...}`,
+    /// `\uHHHH`, `\u{...}`, octal `\0oo`, or escaped punctuation
+    fn parse_char_escape(&mut self) -> Result<char, ParseError> {
+        let start = self.pos - 1;
+        let escaped = match self.current_char() {
+            Some(escaped) => escaped,
+            None => return Err(self.error_at(start, ParseErrorKind::TrailingBackslash)),
+        };
+        self.advance();
+
+        let ch = match escaped {
+            'a' => '\x07',
+            'e' => '\x1B',
+            'f' => '\x0C',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'v' => '\x0B',
+            'x' => self.parse_hex_escape(start, 2)?,
+            'u' => self.parse_hex_escape(start, 4)?,
+            '0' => self.parse_octal_escape(),
+            ch if ch.is_ascii_alphanumeric() => {
+                return Err(self.error_at(start, ParseErrorKind::UnknownEscape(ch)));
+            }
+            ch => ch,
+        };
+        Ok(ch)
+    }
+
+    /// Parse the code point of `\x` or `\u`: either exactly `digits` hex
+    /// digits or any number of them in braces
+    fn parse_hex_escape(&mut self, start: usize, digits: usize) -> Result<char, ParseError> {
+        let invalid = |parser: &Self| parser.error_at(start, ParseErrorKind::InvalidCodePoint);
+
+        let (hex_start, hex_end) = if self.current_char() == Some('{') {
+            let hex_start = self.pos + 1;
+            let hex_end = (hex_start..self.chars.len())
+                .find(|&i| self.chars[i] == '}')
+                .ok_or_else(|| invalid(self))?;
+            self.pos = hex_end + 1;
+            (hex_start, hex_end)
+        } else {
+            let hex_start = self.pos;
+            if hex_start + digits > self.chars.len() {
+                return Err(invalid(self));
+            }
+            self.pos += digits;
+            (hex_start, self.pos)
+        };
+
+        let hex: String = self.chars[hex_start..hex_end].iter().collect();
+        if hex.is_empty() || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return Err(invalid(self));
+        }
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| invalid(self))
+    }
+
+    /// Parse up to two more octal digits after `\0`
+    fn parse_octal_escape(&mut self) -> char {
+        let mut value = 0;
+        for _ in 0..2 {
+            match self.current_char().and_then(|ch| ch.to_digit(8)) {
+                Some(digit) => {
+                    value = value * 8 + digit;
+                    self.advance();
+                }
+                None => break,
+            }
+        }
+        char::from_u32(value).unwrap()
+    }
+
+    /// Parse `\Q...\E`, returning the chars in between literally. A missing
+    /// `\E` quotes the rest of the pattern
+    fn parse_quoted(&mut self) -> Vec<char> {
+        self.pos += 2; // Skip '\Q'
+
+        let mut quoted = Vec::new();
+        while let Some(ch) = self.current_char() {
+            if ch == '\\' && self.peek_char(1) == Some('E') {
+                self.pos += 2;
+                break;
+            }
+            quoted.push(ch);
+            self.advance();
+        }
+        quoted
+    }
+
+    /// Parse the property after `\p` or `\P`: a single letter like `\pL`
+    /// or a braced name like `\p{Greek}`, where `\p{^Greek}` negates it
+    fn parse_property(&mut self) -> Result<CharSet, ParseError> {
+        let start = self.pos - 2;
+        let name: String = match self.current_char() {
+            Some('{') => {
+                let name_start = self.pos + 1;
+                let name_end = (name_start..self.chars.len())
+                    .find(|&i| self.chars[i] == '}')
+                    .ok_or_else(|| self.error_at(start, ParseErrorKind::UnclosedProperty))?;
+                self.pos = name_end + 1;
+                self.chars[name_start..name_end].iter().collect()
+            }
+            Some(ch) if ch.is_ascii_alphabetic() => {
+                self.advance();
+                ch.to_string()
+            }
+            _ => return Err(self.error_at(start, ParseErrorKind::UnclosedProperty)),
+        };
+
+        let (negated, property_name) = match name.strip_prefix('^') {
+            Some(property_name) => (true, property_name),
+            None => (false, name.as_str()),
+        };
+        match unicode::property(property_name) {
+            Some(set) if negated => Ok(set.negated()),
+            Some(set) => Ok(set),
+            None => Err(self.error_at(start, ParseErrorKind::UnknownProperty(name))),
+        }
+    }
+
+    /// Build an error for the character at `pos`, converting the char index
+    /// into a byte offset into the original pattern
+    fn error_at(&self, pos: usize, kind: ParseErrorKind) -> ParseError {
+        let offset = self.chars[..pos].iter().map(|ch| ch.len_utf8()).sum();
+        ParseError { offset, kind }
+    }
+
+    fn current_char(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_char(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+    }
+}
@@ -0,0 +1,535 @@
+//! Thompson NFA compilation and a Pike VM that runs it
+//!
+//! The VM advances every live thread in lockstep, one input char at a time,
+//! so matching takes O(pattern × input) time no matter how the pattern is
+//! nested. Threads are kept in priority order, which gives the same
+//! leftmost-first results as the backtracker. Patterns that need
+//! backreferences, lookaround or atomic groups don't compile; the caller
+//! falls back to the backtracker for those.
+
+use std::collections::HashSet;
+
+use crate::token::Token;
+
+/// Programs larger than this are left to the backtracker, since counted
+/// repetition like `(a{1000}){1000}` expands multiplicatively
+const MAX_INSTRUCTIONS: usize = 100_000;
+
+/// Loops nested deeper than this are left to the backtracker, since each
+/// level takes a bit of the per-path loop state
+const MAX_LOOP_DEPTH: usize = 64;
+
+/// Instructions inside at most this many loops get a visited flag for every
+/// combination of loop bits; deeper ones fall back to a hash set
+const DENSE_LOOP_DEPTH: usize = 4;
+
+/// A single NFA instruction
+#[derive(Debug, Clone)]
+enum Inst {
+    Consume(Token),      // Match one char against a leaf token
+    Assert(Token),       // Zero-width anchor or word boundary
+    Split(usize, usize), // Fork, preferring the first target
+    Jump(usize),         // Continue at another instruction
+    Save(usize),         // Record the current position in a capture slot
+    // End of a loop body: go on to the next iteration at `repeat`, or
+    // straight to `exit` if this one was empty, going by where it started
+    // in slot `mark`
+    LoopEnd {
+        mark: usize,
+        repeat: usize,
+        exit: usize,
+    },
+    Match, // The whole pattern matched
+}
+
+/// A compiled pattern
+#[derive(Debug, Clone)]
+pub struct Program {
+    insts: Vec<Inst>,
+    // How many loops enclose each instruction
+    depths: Vec<usize>,
+    // Where each instruction's visited flags start, one per combination of
+    // loop bits up to `DENSE_LOOP_DEPTH`, with the total at the end
+    keys: Vec<usize>,
+    // Capture slots, followed by one slot per loop for where its current
+    // iteration started
+    captures: usize,
+    slots: usize,
+}
+
+/// Capture positions of one thread: slots 2n and 2n+1 hold the start and
+/// end of group n, with group 0 spanning the whole match
+pub type Slots = Vec<Option<usize>>;
+
+impl Program {
+    /// Compile a token tree with `groups` capture groups, or return `None`
+    /// if the pattern needs the backtracker
+    pub fn compile(tokens: &[Token], groups: usize) -> Option<Self> {
+        let captures = 2 * (groups + 1);
+        let mut compiler = Compiler {
+            insts: Vec::new(),
+            depths: Vec::new(),
+            depth: 0,
+            slots: captures,
+        };
+        compiler.push(Inst::Save(0))?;
+        compiler.sequence(tokens)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+
+        let mut keys = vec![0];
+        for &depth in &compiler.depths {
+            let flags = if depth <= DENSE_LOOP_DEPTH {
+                1 << depth
+            } else {
+                1
+            };
+            keys.push(keys[keys.len() - 1] + flags);
+        }
+
+        Some(Self {
+            insts: compiler.insts,
+            depths: compiler.depths,
+            keys,
+            captures,
+            slots: compiler.slots,
+        })
+    }
+
+    /// Find the leftmost-first match starting at or after `start`. Only
+    /// position `start` is tried when `anchored` is set.
+    pub fn search(
+        &self,
+        chars: &[char],
+        start: usize,
+        anchored: bool,
+        ascii: bool,
+    ) -> Option<Slots> {
+        let keys = self.keys[self.insts.len()];
+        let mut current = Threads::new(keys);
+        let mut next = Threads::new(keys);
+        let mut stack = Vec::new();
+        let mut matched = None;
+
+        for pos in start..=chars.len() {
+            // Threads seeded later start further right, so they come last
+            if matched.is_none() && (pos == start || !anchored) {
+                let slots = vec![None; self.slots];
+                self.add_thread(&mut current, &mut stack, 0, slots, chars, pos, ascii);
+            }
+            if current.is_empty() && (matched.is_some() || anchored) {
+                break;
+            }
+
+            for (pc, slots) in current.threads.drain(..) {
+                match &self.insts[pc] {
+                    Inst::Consume(token) => {
+                        if pos < chars.len() && token.matches_char(chars[pos], ascii) {
+                            self.add_thread(
+                                &mut next,
+                                &mut stack,
+                                pc + 1,
+                                slots,
+                                chars,
+                                pos + 1,
+                                ascii,
+                            );
+                        }
+                    }
+                    Inst::Match => {
+                        // Everything after this thread has lower priority
+                        matched = Some(slots);
+                        break;
+                    }
+                    _ => unreachable!("only consuming and matching threads are queued"),
+                }
+            }
+
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+
+        matched.map(|mut slots| {
+            slots.truncate(self.captures);
+            slots
+        })
+    }
+
+    /// Follow the epsilon transitions from `pc`, queueing every reachable
+    /// `Consume` or `Match` instruction in priority order. Each path keeps
+    /// a bit per enclosing loop, by depth, for whether that loop's current
+    /// iteration started at `pos`. Those bits decide where every `LoopEnd`
+    /// ahead goes, so an instruction is only skipped when a path with the
+    /// same bits got there first, and an empty final iteration isn't cut
+    /// short by the path that ended the previous one.
+    #[allow(clippy::too_many_arguments)]
+    fn add_thread(
+        &self,
+        threads: &mut Threads,
+        stack: &mut Vec<Frame>,
+        pc: usize,
+        mut slots: Slots,
+        chars: &[char],
+        pos: usize,
+        ascii: bool,
+    ) {
+        stack.push(Frame::Explore(pc, 0));
+
+        while let Some(frame) = stack.pop() {
+            let (pc, fresh) = match frame {
+                Frame::Explore(pc, fresh) => (pc, fresh),
+                Frame::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            // Threads that go on to consume only differ in their slots, so
+            // the first one to get there wins either way. Otherwise only the
+            // loops around this instruction matter; any others have ended.
+            let queued = matches!(self.insts[pc], Inst::Consume(_) | Inst::Match);
+            let enclosing = 1u64
+                .checked_shl(self.depths[pc] as u32)
+                .map_or(u64::MAX, |bit| bit - 1);
+            let fresh = if queued { 0 } else { fresh & enclosing };
+            let first_visit = if fresh < (self.keys[pc + 1] - self.keys[pc]) as u64 {
+                threads.visit(self.keys[pc] + fresh as usize)
+            } else {
+                threads.deep.insert((pc, fresh))
+            };
+            if !first_visit {
+                continue;
+            }
+
+            match &self.insts[pc] {
+                Inst::Consume(_) | Inst::Match => threads.threads.push((pc, slots.clone())),
+                Inst::Assert(token) => {
+                    if token.assertion_holds(chars, pos, ascii) {
+                        stack.push(Frame::Explore(pc + 1, fresh));
+                    }
+                }
+                Inst::Split(first, second) => {
+                    stack.push(Frame::Explore(*second, fresh));
+                    stack.push(Frame::Explore(*first, fresh));
+                }
+                Inst::Jump(target) => stack.push(Frame::Explore(*target, fresh)),
+                Inst::LoopEnd { mark, repeat, exit } => {
+                    // Like the backtracker, keep an empty iteration's
+                    // captures but don't go round again
+                    let target = if slots[*mark] == Some(pos) {
+                        exit
+                    } else {
+                        repeat
+                    };
+                    stack.push(Frame::Explore(*target, fresh));
+                }
+                Inst::Save(slot) => {
+                    // Undo the save once this branch has been explored
+                    stack.push(Frame::Restore(*slot, slots[*slot]));
+                    slots[*slot] = Some(pos);
+                    // Saving a loop mark starts an iteration of the loop at
+                    // this depth here
+                    let fresh = if *slot >= self.captures {
+                        fresh | 1 << self.depths[pc]
+                    } else {
+                        fresh
+                    };
+                    stack.push(Frame::Explore(pc + 1, fresh));
+                }
+            }
+        }
+    }
+
+    /// Follow the epsilon transitions from `kernel` without tracking
+    /// captures, returning the reachable consuming instructions in order and
+    /// whether `Match` is reachable. Assertions are evaluated at `pos` in
+    /// `window`, which only needs the chars either side of it.
+    pub fn closure(
+        &self,
+        kernel: &[usize],
+        window: &[char],
+        pos: usize,
+        ascii: bool,
+    ) -> (Vec<usize>, bool) {
+        let mut seen = vec![false; self.insts.len()];
+        let mut stack: Vec<usize> = kernel.iter().rev().copied().collect();
+        let mut consuming = Vec::new();
+        let mut matched = false;
+
+        while let Some(pc) = stack.pop() {
+            if std::mem::replace(&mut seen[pc], true) {
+                continue;
+            }
+            match &self.insts[pc] {
+                Inst::Consume(_) => consuming.push(pc),
+                Inst::Match => matched = true,
+                Inst::Assert(token) => {
+                    if token.assertion_holds(window, pos, ascii) {
+                        stack.push(pc + 1);
+                    }
+                }
+                Inst::Split(first, second) => {
+                    stack.push(*second);
+                    stack.push(*first);
+                }
+                Inst::Jump(target) => stack.push(*target),
+                // Without slots, either way might be taken; going round
+                // again reaches the exit too
+                Inst::LoopEnd { repeat, .. } => stack.push(*repeat),
+                Inst::Save(_) => stack.push(pc + 1),
+            }
+        }
+
+        consuming.sort_unstable();
+        (consuming, matched)
+    }
+
+    /// Advance the consuming instructions in `pcs` over `ch`, returning the
+    /// instructions that follow the ones that accepted it
+    pub fn step(&self, pcs: &[usize], ch: char, ascii: bool) -> Vec<usize> {
+        pcs.iter()
+            .filter(|&&pc| matches!(&self.insts[pc], Inst::Consume(token) if token.matches_char(ch, ascii)))
+            .map(|pc| pc + 1)
+            .collect()
+    }
+}
+
+/// Pending work in the explicit epsilon-closure stack
+enum Frame {
+    // An instruction, and a bit per enclosing loop depth for whether the
+    // path to it started that loop's iteration at the current position
+    Explore(usize, u64),
+    Restore(usize, Option<usize>),
+}
+
+/// Threads for one input position, with a sparse set so each instruction is
+/// queued at most once
+struct Threads {
+    threads: Vec<(usize, Slots)>,
+    sparse: Vec<usize>,
+    dense: Vec<usize>,
+    // Instructions too deep in loops for a flag per combination of loop
+    // bits, visited with some bits set
+    deep: HashSet<(usize, u64)>,
+}
+
+impl Threads {
+    fn new(size: usize) -> Self {
+        Self {
+            threads: Vec::new(),
+            sparse: vec![0; size],
+            dense: Vec::with_capacity(size),
+            deep: HashSet::new(),
+        }
+    }
+
+    /// Mark `key` as visited, returning false if it already was
+    fn visit(&mut self, key: usize) -> bool {
+        let index = self.sparse[key];
+        if index < self.dense.len() && self.dense[index] == key {
+            return false;
+        }
+        self.sparse[key] = self.dense.len();
+        self.dense.push(key);
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.threads.clear();
+        self.dense.clear();
+        self.deep.clear();
+    }
+}
+
+struct Compiler {
+    insts: Vec<Inst>,
+    depths: Vec<usize>,
+    // Loops around the instructions being compiled
+    depth: usize,
+    // Slots used so far, counting loop marks
+    slots: usize,
+}
+
+impl Compiler {
+    /// Append an instruction and return its address
+    fn push(&mut self, inst: Inst) -> Option<usize> {
+        if self.insts.len() >= MAX_INSTRUCTIONS {
+            return None;
+        }
+        self.insts.push(inst);
+        self.depths.push(self.depth);
+        Some(self.insts.len() - 1)
+    }
+
+    /// Point a placeholder `Split`, `Jump` or `LoopEnd` at its final targets
+    fn patch(&mut self, at: usize, inst: Inst) {
+        self.insts[at] = inst;
+    }
+
+    fn next_pc(&self) -> usize {
+        self.insts.len()
+    }
+
+    fn sequence(&mut self, tokens: &[Token]) -> Option<()> {
+        tokens.iter().try_for_each(|token| self.token(token))
+    }
+
+    fn token(&mut self, token: &Token) -> Option<()> {
+        match token {
+            Token::Char(_)
+            | Token::Dot
+            | Token::Digit
+            | Token::Word
+            | Token::Whitespace
+            | Token::CharClass(_)
+            | Token::NegCharClass(_) => {
+                self.push(Inst::Consume(token.clone()))?;
+            }
+
+            Token::StartAnchor
+            | Token::EndAnchor
+            | Token::EndOfText
+            | Token::WordBoundary
+            | Token::NotWordBoundary
+            | Token::StartOfWord
+            | Token::EndOfWord => {
+                self.push(Inst::Assert(token.clone()))?;
+            }
+
+            Token::IgnoreCase(inner) => match inner.as_ref() {
+                Token::Backreference(_) | Token::NamedBackreference(_) => return None,
+                _ => {
+                    self.push(Inst::Consume(token.clone()))?;
+                }
+            },
+
+            Token::Group(tokens, group_num) => {
+                self.push(Inst::Save(2 * group_num))?;
+                self.sequence(tokens)?;
+                self.push(Inst::Save(2 * group_num + 1))?;
+            }
+
+            Token::NonCapturing(tokens) => self.sequence(tokens)?,
+
+            Token::Alternative(alternatives) => self.alternation(alternatives)?,
+
+            Token::Repeat {
+                token,
+                min,
+                max,
+                greedy,
+            } => self.repeat(token, *min, *max, *greedy)?,
+
+            Token::Backreference(_)
+            | Token::NamedBackreference(_)
+            | Token::Atomic(_)
+            | Token::LookAhead { .. }
+            | Token::LookBehind { .. } => return None,
+        }
+        Some(())
+    }
+
+    fn alternation(&mut self, alternatives: &[Vec<Token>]) -> Option<()> {
+        let mut exits = Vec::new();
+
+        for (i, alternative) in alternatives.iter().enumerate() {
+            if i + 1 == alternatives.len() {
+                self.sequence(alternative)?;
+                break;
+            }
+            let split = self.push(Inst::Split(0, 0))?;
+            self.sequence(alternative)?;
+            exits.push(self.push(Inst::Jump(0))?);
+            let next = self.next_pc();
+            self.patch(split, Inst::Split(split + 1, next));
+        }
+
+        let end = self.next_pc();
+        for exit in exits {
+            self.patch(exit, Inst::Jump(end));
+        }
+        Some(())
+    }
+
+    fn repeat(
+        &mut self,
+        token: &Token,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    ) -> Option<()> {
+        for _ in 0..min {
+            self.token(token)?;
+        }
+
+        let fork = |body, exit| {
+            if greedy {
+                Inst::Split(body, exit)
+            } else {
+                Inst::Split(exit, body)
+            }
+        };
+
+        match max {
+            None => {
+                let mark = self.slots;
+                self.slots += 1;
+
+                let split = self.push(Inst::Split(0, 0))?;
+                self.push(Inst::Save(mark))?;
+                let end = self.loop_body(token, mark)?;
+                let exit = self.next_pc();
+                self.patch(split, fork(split + 1, exit));
+                self.patch(
+                    end,
+                    Inst::LoopEnd {
+                        mark,
+                        repeat: split,
+                        exit,
+                    },
+                );
+            }
+            Some(max) => {
+                // Each optional copy can bail out straight to the end, and
+                // an empty one ends the repetition like a loop would
+                let mark = self.slots;
+                self.slots += 1;
+
+                let mut copies = Vec::new();
+                for _ in min..max {
+                    let split = self.push(Inst::Split(0, 0))?;
+                    self.push(Inst::Save(mark))?;
+                    let end = self.loop_body(token, mark)?;
+                    copies.push((split, end));
+                }
+                let exit = self.next_pc();
+                for (split, end) in copies {
+                    self.patch(split, fork(split + 1, exit));
+                    let repeat = end + 1;
+                    self.patch(end, Inst::LoopEnd { mark, repeat, exit });
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Compile one iteration of a loop followed by a placeholder `LoopEnd`,
+    /// one level deeper than the loop itself, and return the `LoopEnd`
+    fn loop_body(&mut self, token: &Token, mark: usize) -> Option<usize> {
+        if self.depth == MAX_LOOP_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        self.token(token)?;
+        let end = self.push(Inst::LoopEnd {
+            mark,
+            repeat: 0,
+            exit: 0,
+        })?;
+        self.depth -= 1;
+        Some(end)
+    }
+}
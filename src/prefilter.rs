@@ -0,0 +1,133 @@
+//! Required-literal prefilter
+//!
+//! Most patterns contain a run of plain characters that every match has to
+//! include. Searching for that run's UTF-8 bytes with `memchr` is much
+//! cheaper than running any of the matchers, so lines without it are
+//! rejected up front. When the run starts the pattern, its occurrences are
+//! also the only places a match can begin.
+
+use memchr::memmem::Finder;
+
+use crate::token::Token;
+
+#[derive(Debug, Clone)]
+pub struct Prefilter {
+    finder: Finder<'static>,
+    // Every match starts with the literal
+    prefix: bool,
+}
+
+impl Prefilter {
+    /// Pick the longest literal that every match of `tokens` contains,
+    /// preferring one that starts the match. Returns `None` if there isn't
+    /// one, e.g. for case-insensitive patterns.
+    pub fn new(tokens: &[Token]) -> Option<Self> {
+        let mut scan = Scan {
+            runs: Vec::new(),
+            current: String::new(),
+            current_prefix: false,
+            at_start: true,
+        };
+        scan.sequence(tokens);
+        scan.end_run();
+
+        scan.runs
+            .into_iter()
+            .max_by_key(|(literal, prefix)| (literal.chars().count(), *prefix))
+            .map(|(literal, prefix)| Self {
+                finder: Finder::new(literal.as_bytes()).into_owned(),
+                prefix,
+            })
+    }
+
+    /// Whether `input` could contain a match at all
+    pub fn could_match(&self, input: &[u8]) -> bool {
+        self.finder.find(input).is_some()
+    }
+
+    /// Byte offsets from `from` onwards where a match could start, in
+    /// increasing order, or `None` if the literal doesn't start the pattern
+    pub fn candidates<'a>(
+        &'a self,
+        input: &'a [u8],
+        mut from: usize,
+    ) -> Option<impl Iterator<Item = usize> + 'a> {
+        if !self.prefix {
+            return None;
+        }
+
+        // Occurrences may overlap, so resume one byte after each. The
+        // literal is valid UTF-8, so it can only be found on char boundaries.
+        Some(std::iter::from_fn(move || {
+            let offset = from + self.finder.find(input.get(from..)?)?;
+            from = offset + 1;
+            Some(offset)
+        }))
+    }
+}
+
+/// Walks a token tree collecting runs of literal chars that must appear
+/// contiguously in every match
+struct Scan {
+    runs: Vec<(String, bool)>,
+    current: String,
+    current_prefix: bool,
+    // Nothing that consumes input has been seen yet
+    at_start: bool,
+}
+
+impl Scan {
+    fn sequence(&mut self, tokens: &[Token]) {
+        for token in tokens {
+            self.token(token);
+        }
+    }
+
+    fn token(&mut self, token: &Token) {
+        match token {
+            Token::Char(ch) => {
+                if self.current.is_empty() {
+                    self.current_prefix = self.at_start;
+                }
+                self.current.push(*ch);
+                self.at_start = false;
+            }
+
+            // Groups must match their whole body, in place
+            Token::Group(tokens, _) | Token::NonCapturing(tokens) | Token::Atomic(tokens) => {
+                self.sequence(tokens)
+            }
+
+            // The first iteration is required; later ones may or may not
+            // follow it
+            Token::Repeat { token, min, .. } if *min >= 1 => {
+                self.token(token);
+                self.end_run();
+                self.at_start = false;
+            }
+
+            // Zero-width tokens don't separate the chars either side
+            Token::StartAnchor
+            | Token::EndAnchor
+            | Token::EndOfText
+            | Token::WordBoundary
+            | Token::NotWordBoundary
+            | Token::StartOfWord
+            | Token::EndOfWord
+            | Token::LookAhead { .. }
+            | Token::LookBehind { .. } => {}
+
+            _ => {
+                self.end_run();
+                self.at_start = false;
+            }
+        }
+    }
+
+    fn end_run(&mut self) {
+        if !self.current.is_empty() {
+            let literal = std::mem::take(&mut self.current);
+            self.runs.push((literal, self.current_prefix));
+        }
+    }
+}
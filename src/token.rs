@@ -0,0 +1,136 @@
+//! The parsed form of a pattern
+
+use crate::charset::{fold_case, CharSet, Shorthand};
+
+/// Represents different types of regex tokens
+#[derive(Debug, Clone)]
+pub enum Token {
+    Char(char),                   // Literal character
+    Dot,                          // . matches any character
+    StartAnchor,                  // ^ and \A match at the start of input
+    EndAnchor,                    // $ and \Z match at the end or before a final newline
+    EndOfText,                    // \z matches only at the very end of input
+    WordBoundary,                 // \b between a word and a non-word character
+    NotWordBoundary,              // \B anywhere \b doesn't match
+    StartOfWord,                  // \< non-word character (or start) then word
+    EndOfWord,                    // \> word then non-word character (or end)
+    Digit,                        // \d matches digits
+    Word,                         // \w matches word characters
+    Whitespace,                   // \s matches whitespace
+    CharClass(CharSet),           // [abc] character class
+    NegCharClass(CharSet),        // [^abc] negated character class
+    Group(Vec<Token>, usize),     // (pattern) with group number
+    NonCapturing(Vec<Token>),     // (?:pattern) groups without capturing
+    Atomic(Vec<Token>),           // (?>pattern) never backtracked into
+    Alternative(Vec<Vec<Token>>), // a|b alternatives
+    Backreference(usize),         // \1 backreference
+    NamedBackreference(String),   // \k<name> backreference
+    IgnoreCase(Box<Token>),       // literal, class or backreference under (?i)
+    Repeat {
+        // a*, a+, a?, a{n,m} and their lazy forms
+        token: Box<Token>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+    LookAhead {
+        // (?=...) or, when negated, (?!...)
+        tokens: Vec<Token>,
+        negated: bool,
+    },
+    LookBehind {
+        // (?<=...) or (?<!...), with the longest text the body can match
+        tokens: Vec<Token>,
+        negated: bool,
+        max_width: Option<usize>,
+    },
+}
+
+impl Token {
+    /// Whether a token that consumes exactly one char accepts `ch`; false
+    /// for every other kind of token
+    pub fn matches_char(&self, ch: char, ascii: bool) -> bool {
+        match self {
+            Token::Char(expected) => ch == *expected,
+            Token::Dot => true,
+            Token::Digit => Shorthand::Digit.matches(ch, ascii),
+            Token::Word => Shorthand::Word.matches(ch, ascii),
+            Token::Whitespace => Shorthand::Whitespace.matches(ch, ascii),
+            Token::CharClass(allowed) => allowed.contains(ch),
+            Token::NegCharClass(forbidden) => !forbidden.contains(ch),
+            // The parser already folded the literal or class
+            Token::IgnoreCase(inner) => inner.matches_char(fold_case(ch), ascii),
+            _ => false,
+        }
+    }
+
+    /// Whether a zero-width anchor or word boundary holds at `pos`; false
+    /// for every other kind of token
+    pub fn assertion_holds(&self, chars: &[char], pos: usize, ascii: bool) -> bool {
+        let is_word = |ch| Shorthand::Word.matches(ch, ascii);
+        let word_before = pos > 0 && is_word(chars[pos - 1]);
+        let word_after = pos < chars.len() && is_word(chars[pos]);
+
+        match self {
+            Token::StartAnchor => pos == 0,
+            Token::EndAnchor => {
+                pos == chars.len() || (pos + 1 == chars.len() && chars[pos] == '\n')
+            }
+            Token::EndOfText => pos == chars.len(),
+            Token::WordBoundary => word_before != word_after,
+            Token::NotWordBoundary => word_before == word_after,
+            Token::StartOfWord => !word_before && word_after,
+            Token::EndOfWord => word_before && !word_after,
+            _ => false,
+        }
+    }
+}
+
+/// Longest text, in chars, that a token sequence can match, or `None` if
+/// it's unbounded
+pub fn max_width(tokens: &[Token]) -> Option<usize> {
+    tokens.iter().try_fold(0, |total: usize, token| {
+        let width = match token {
+            Token::Group(tokens, _) | Token::NonCapturing(tokens) | Token::Atomic(tokens) => {
+                max_width(tokens)?
+            }
+            Token::IgnoreCase(token) => max_width(std::slice::from_ref(token))?,
+            Token::Alternative(alternatives) => alternatives
+                .iter()
+                .map(|alt| max_width(alt))
+                .try_fold(0, |widest, width| Some(widest.max(width?)))?,
+            Token::Repeat { token, max, .. } => {
+                max_width(std::slice::from_ref(token))?.checked_mul((*max)?)?
+            }
+            Token::Backreference(_) | Token::NamedBackreference(_) => return None,
+            Token::StartAnchor
+            | Token::EndAnchor
+            | Token::EndOfText
+            | Token::WordBoundary
+            | Token::NotWordBoundary
+            | Token::StartOfWord
+            | Token::EndOfWord
+            | Token::LookAhead { .. }
+            | Token::LookBehind { .. } => 0,
+            _ => 1,
+        };
+        total.checked_add(width)
+    })
+}
+
+/// Whether any token refers back to a capture group
+pub fn has_backreferences(tokens: &[Token]) -> bool {
+    tokens.iter().any(|token| match token {
+        Token::Backreference(_) | Token::NamedBackreference(_) => true,
+        Token::Group(tokens, _)
+        | Token::NonCapturing(tokens)
+        | Token::Atomic(tokens)
+        | Token::LookAhead { tokens, .. }
+        | Token::LookBehind { tokens, .. } => has_backreferences(tokens),
+        Token::IgnoreCase(token) | Token::Repeat { token, .. } => {
+            has_backreferences(std::slice::from_ref(token))
+        }
+        Token::Alternative(alternatives) => alternatives.iter().any(|alt| has_backreferences(alt)),
+        _ => false,
+    })
+}
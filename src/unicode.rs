@@ -0,0 +1,155 @@
+//! Unicode properties for `\p{...}` and the Unicode forms of `\w` and `\d`,
+//! built from the generated tables in `unicode_tables`
+
+use std::sync::OnceLock;
+
+use crate::charset::CharSet;
+use crate::unicode_tables::{GENERAL_CATEGORIES, SCRIPTS, SCRIPT_ALIASES};
+
+/// Long names of the two-letter general categories
+const CATEGORY_NAMES: &[(&str, &str)] = &[
+    ("Cc", "Control"),
+    ("Cf", "Format"),
+    ("Cn", "Unassigned"),
+    ("Co", "Private_Use"),
+    ("Cs", "Surrogate"),
+    ("Ll", "Lowercase_Letter"),
+    ("Lm", "Modifier_Letter"),
+    ("Lo", "Other_Letter"),
+    ("Lt", "Titlecase_Letter"),
+    ("Lu", "Uppercase_Letter"),
+    ("Mc", "Spacing_Mark"),
+    ("Me", "Enclosing_Mark"),
+    ("Mn", "Nonspacing_Mark"),
+    ("Nd", "Decimal_Number"),
+    ("Nl", "Letter_Number"),
+    ("No", "Other_Number"),
+    ("Pc", "Connector_Punctuation"),
+    ("Pd", "Dash_Punctuation"),
+    ("Pe", "Close_Punctuation"),
+    ("Pf", "Final_Punctuation"),
+    ("Pi", "Initial_Punctuation"),
+    ("Po", "Other_Punctuation"),
+    ("Ps", "Open_Punctuation"),
+    ("Sc", "Currency_Symbol"),
+    ("Sk", "Modifier_Symbol"),
+    ("Sm", "Math_Symbol"),
+    ("So", "Other_Symbol"),
+    ("Zl", "Line_Separator"),
+    ("Zp", "Paragraph_Separator"),
+    ("Zs", "Space_Separator"),
+];
+
+/// Categories made up of several two-letter ones
+const CATEGORY_GROUPS: &[(&str, &str, &[&str])] = &[
+    ("C", "Other", &["Cc", "Cf", "Cn", "Co", "Cs"]),
+    ("L", "Letter", &["Ll", "Lm", "Lo", "Lt", "Lu"]),
+    ("LC", "Cased_Letter", &["Ll", "Lt", "Lu"]),
+    ("M", "Mark", &["Mc", "Me", "Mn"]),
+    ("N", "Number", &["Nd", "Nl", "No"]),
+    (
+        "P",
+        "Punctuation",
+        &["Pc", "Pd", "Pe", "Pf", "Pi", "Po", "Ps"],
+    ),
+    ("S", "Symbol", &["Sc", "Sk", "Sm", "So"]),
+    ("Z", "Separator", &["Zl", "Zp", "Zs"]),
+];
+
+/// Look up the name inside `\p{...}`: a general category, a script, one of
+/// `Any`, `ASCII` or `Assigned`, or an explicit `gc=...` / `sc=...`. Names
+/// are compared loosely, ignoring case, spaces, underscores and hyphens
+pub fn property(name: &str) -> Option<CharSet> {
+    match name.split_once(['=', ':']) {
+        Some((key, value)) => match loose(key).as_str() {
+            "gc" | "generalcategory" => general_category(value),
+            "sc" | "script" => script(value),
+            _ => None,
+        },
+        None => general_category(name)
+            .or_else(|| script(name))
+            .or_else(|| special(name)),
+    }
+}
+
+/// `\w` outside ASCII mode: letters, marks, decimal digits and connector
+/// punctuation
+pub fn word() -> &'static CharSet {
+    static WORD: OnceLock<CharSet> = OnceLock::new();
+    WORD.get_or_init(|| categories(&["Ll", "Lm", "Lo", "Lt", "Lu", "Mc", "Me", "Mn", "Nd", "Pc"]))
+}
+
+/// `\d` outside ASCII mode: every decimal digit
+pub fn digit() -> &'static CharSet {
+    static DIGIT: OnceLock<CharSet> = OnceLock::new();
+    DIGIT.get_or_init(|| categories(&["Nd"]))
+}
+
+fn general_category(name: &str) -> Option<CharSet> {
+    let name = loose(name);
+
+    if let Some(&(short, _)) = CATEGORY_NAMES
+        .iter()
+        .find(|(short, long)| loose(short) == name || loose(long) == name)
+    {
+        return Some(categories(&[short]));
+    }
+
+    CATEGORY_GROUPS
+        .iter()
+        .find(|(short, long, _)| loose(short) == name || loose(long) == name)
+        .map(|(_, _, members)| categories(members))
+}
+
+fn script(name: &str) -> Option<CharSet> {
+    let name = loose(name);
+    let long_name = SCRIPT_ALIASES
+        .iter()
+        .find(|(alias, _)| loose(alias) == name)
+        .map_or(name, |(_, long_name)| loose(long_name));
+
+    SCRIPTS
+        .iter()
+        .find(|(script, _)| loose(script) == long_name)
+        .map(|(_, ranges)| CharSet::from_ranges(ranges.iter().copied()))
+}
+
+fn special(name: &str) -> Option<CharSet> {
+    match loose(name).as_str() {
+        "any" => Some(CharSet::from_ranges([('\0', char::MAX)])),
+        "ascii" => Some(CharSet::from_ranges([('\0', '\x7F')])),
+        "assigned" => Some(unassigned().negated()),
+        _ => None,
+    }
+}
+
+/// Union of two-letter categories. Cs is always empty since surrogates
+/// aren't chars, and Cn is whatever no other category covers
+fn categories(names: &[&str]) -> CharSet {
+    let ranges = GENERAL_CATEGORIES
+        .iter()
+        .filter(|(category, _)| names.contains(category))
+        .flat_map(|(_, ranges)| ranges.iter().copied());
+
+    let set = CharSet::from_ranges(ranges);
+    if names.contains(&"Cn") {
+        set.union(&unassigned())
+    } else {
+        set
+    }
+}
+
+fn unassigned() -> CharSet {
+    let assigned = GENERAL_CATEGORIES
+        .iter()
+        .flat_map(|(_, ranges)| ranges.iter().copied());
+    CharSet::from_ranges(assigned).negated()
+}
+
+/// Normalize a property name for loose matching
+fn loose(name: &str) -> String {
+    name.chars()
+        .filter(|ch| !matches!(ch, ' ' | '_' | '-'))
+        .map(|ch| ch.to_ascii_lowercase())
+        .collect()
+}
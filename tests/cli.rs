@@ -0,0 +1,139 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// A scratch directory for one test, holding `fruit.txt` with three lines
+/// that contain an 'a' and `berry.txt` with one that doesn't
+fn fixture(test: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("fruit.txt"), "apple\nbanana\navocado\n").unwrap();
+    fs::write(dir.join("berry.txt"), "cherry\n").unwrap();
+    dir
+}
+
+/// Run the binary in `dir` with `stdin` as its input, returning what it
+/// printed and its exit code
+fn grep(dir: &PathBuf, args: &[&str], stdin: &str) -> (String, i32) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_codecrafters-grep"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (stdout, output.status.code().unwrap())
+}
+
+#[test]
+fn count_prints_selected_lines_per_file() {
+    let dir = fixture("count");
+    let files = ["fruit.txt", "berry.txt"];
+
+    let args = [&["-c", "a"][..], &files].concat();
+    assert_eq!(
+        grep(&dir, &args, ""),
+        ("fruit.txt:3\nberry.txt:0\n".into(), 0)
+    );
+    let args = [&["-vc", "a"][..], &files].concat();
+    assert_eq!(
+        grep(&dir, &args, ""),
+        ("fruit.txt:0\nberry.txt:1\n".into(), 0)
+    );
+    assert_eq!(grep(&dir, &["-c", "z"], "a\nb\n"), ("0\n".into(), 1));
+}
+
+#[test]
+fn list_files_with_and_without_matches() {
+    let dir = fixture("list");
+    let files = ["fruit.txt", "berry.txt"];
+
+    let args = [&["-l", "a"][..], &files].concat();
+    assert_eq!(grep(&dir, &args, ""), ("fruit.txt\n".into(), 0));
+    let args = [&["-L", "a"][..], &files].concat();
+    assert_eq!(grep(&dir, &args, ""), ("berry.txt\n".into(), 0));
+    // -L succeeds when any line was selected, even if it lists nothing
+    assert_eq!(grep(&dir, &["-L", "a", "fruit.txt"], ""), ("".into(), 0));
+    assert_eq!(
+        grep(&dir, &["-L", "z", "fruit.txt"], ""),
+        ("fruit.txt\n".into(), 1)
+    );
+    // -l beats -c
+    let args = [&["-cl", "a"][..], &files].concat();
+    assert_eq!(grep(&dir, &args, ""), ("fruit.txt\n".into(), 0));
+}
+
+#[test]
+fn quiet_only_sets_the_exit_code() {
+    let dir = fixture("quiet");
+
+    assert_eq!(grep(&dir, &["-q", "a", "fruit.txt"], ""), ("".into(), 0));
+    assert_eq!(grep(&dir, &["-q", "z", "fruit.txt"], ""), ("".into(), 1));
+    assert_eq!(grep(&dir, &["-ql", "a", "fruit.txt"], ""), ("".into(), 0));
+    // A match wins over a missing file with -q, but not without it
+    let args = ["-q", "a", "missing.txt", "fruit.txt"];
+    assert_eq!(grep(&dir, &args, ""), ("".into(), 0));
+    let args = ["-c", "a", "missing.txt", "fruit.txt"];
+    assert_eq!(grep(&dir, &args, ""), ("fruit.txt:3\n".into(), 2));
+}
+
+#[test]
+fn max_count_stops_after_selected_lines() {
+    let dir = fixture("max-count");
+
+    assert_eq!(
+        grep(&dir, &["-m", "2", "a", "fruit.txt"], ""),
+        ("apple\nbanana\n".into(), 0)
+    );
+    assert_eq!(
+        grep(&dir, &["-m1", "-c", "a", "fruit.txt"], ""),
+        ("1\n".into(), 0)
+    );
+    assert_eq!(
+        grep(&dir, &["-m", "1", "-v", "b"], "ab\ncd\nef\n"),
+        ("cd\n".into(), 0)
+    );
+    assert_eq!(
+        grep(&dir, &["-m", "0", "a", "fruit.txt"], ""),
+        ("".into(), 1)
+    );
+    assert_eq!(grep(&dir, &["-m", "x", "a"], ""), ("".into(), 2));
+}
+
+#[test]
+fn carriage_returns_stay_in_the_line() {
+    let dir = fixture("crlf");
+
+    assert_eq!(grep(&dir, &["-c", "o$"], "foo\r\nbar\n"), ("0\n".into(), 1));
+    assert_eq!(grep(&dir, &["foo"], "foo\r\n"), ("foo\r\n".into(), 0));
+}
+
+#[cfg(unix)]
+#[test]
+fn file_names_need_not_be_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = fixture("non-utf8");
+    let name = OsStr::from_bytes(b"caf\xe9.txt");
+    fs::write(dir.join(name), "latte\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-grep"))
+        .args([OsStr::new("-c"), OsStr::new("t"), name])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout, b"1\n");
+    assert_eq!(output.status.code(), Some(0));
+}